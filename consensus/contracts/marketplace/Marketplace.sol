@@ -81,6 +81,9 @@ contract Marketplace {
         address gossiper; // the node that first gossiped the order
         uint128 price; // the price of the NFT in payment tokens
         OrderType order_type; // BUY or SELL
+        uint64 expiresAt; // unix timestamp after which the order is invalid, 0 if never
+        uint64 nonce; // signer's order counter
+        uint64 salt; // random value to make identical orders distinct
     }
 
     event Match (
@@ -122,7 +125,10 @@ contract Marketplace {
                 order.nftId, 
                 order.gossiper, 
                 order.price,
-                order.order_type
+                order.order_type,
+                order.expiresAt,
+                order.nonce,
+                order.salt
             )
         );
     }
@@ -140,6 +146,11 @@ contract Marketplace {
             return false;
         }
 
+        // make sure order is not expired
+        if (order.expiresAt != 0 && order.expiresAt < block.timestamp) {
+            return false;
+        }
+
         if (ecrecover(hash, signature.v, signature.r, signature.s) == order.signer) {
             return true;
         }
@@ -1,11 +1,56 @@
 const {expect} = require('chai');
+const { spawn } = require('child_process');
+const path = require('path');
 const client = require('./_grpc-client');
+const { ethers } = require('ethers');
+const { signOrder, signCancel } = require('./_signer');
 const testOrders = require('./test-orders.json');
 
 // GRPC ports of the nodes
 const NODE1_PORT = '50051';
 const NODE2_PORT = '50052';
 const NODE3_PORT = '50053';
+const NODE4_PORT = '50054';
+
+// private keys of the nodes, see the Makefile.
+// The test orders are signed by the same wallets
+const PRIVATE_KEYS = [
+    '0x3014cc8374696c3efbe2617c20d591751f3bb3b6d30df32506b729928b58b836',
+    '0x9221bd3e2a1ccc039b6f7779c26b3a60560421641f1a550d0767e09ace8a77fb',
+    '0x6070a5a5651492a41340b7c4ba2cfbd9d2236d4e1662081448d54ed97e3a0aff',
+    '0x654a23005daa65949a117d2f4f0cd87106304e0e86b26308b879f96f517f6f53',
+];
+
+// private key of the signer of the first test order
+const SIGNER_PRIVATE_KEY = PRIVATE_KEYS[1];
+
+// node address by its gRPC port
+const NODE_ADDRESSES = {
+    [NODE1_PORT]: new ethers.Wallet(PRIVATE_KEYS[0]).address,
+    [NODE2_PORT]: new ethers.Wallet(PRIVATE_KEYS[1]).address,
+    [NODE3_PORT]: new ethers.Wallet(PRIVATE_KEYS[2]).address,
+    [NODE4_PORT]: new ethers.Wallet(PRIVATE_KEYS[3]).address,
+};
+
+// private key of the order signer
+function signerKey(order) {
+    const key = PRIVATE_KEYS.find(key => new ethers.Wallet(key).address.toLowerCase() === order.signer.toLowerCase());
+    if (!key) {
+        throw new Error(`unknown signer ${order.signer}`);
+    }
+    return key;
+}
+
+// order signed by its signer for the node listening on the port
+function signedOrder(order, port) {
+    return signOrder(order, NODE_ADDRESSES[port], signerKey(order));
+}
+
+// create the signed order on the node listening on the port
+async function createOrder(port, order) {
+    const nodeClient = await client(port);
+    return nodeClient.CreateOrderCommitment(signedOrder(order, port));
+}
 
 describe('Network integration tests', function() {
     this.timeout(100000);
@@ -32,14 +77,22 @@ describe('Network integration tests', function() {
     it('Create order commitment works', async function(){
         const client1 = await client(NODE1_PORT);
 
-        const orderCommitment = await client1.CreateOrderCommitment(testOrders[0]);
+        // unsigned orders are rejected
+        await client1.CreateOrderCommitment(testOrders[0])
+            .then(() => expect.fail("unsigned order should be rejected"))
+            .catch(e => expect(e.code).to.equal(3)); // INVALID_ARGUMENT
 
-        const { gossiper, ...orderCommitmentWithoutGossiper } = orderCommitment;
-        const { gossiper: nullGossiper, ...expectedOrderCommitmentWithoutGossiper } = testOrders[0];
-        
-        console.log("Node should populate gossiper field with its address");
+        const request = signedOrder(testOrders[0], NODE1_PORT);
+        const orderCommitment = await client1.CreateOrderCommitment(request);
+
+        const { gossiper, orderId, ...orderCommitmentWithoutGossiper } = orderCommitment;
+        const { gossiper: requestGossiper, orderId: clientOrderId, ...expectedOrderCommitmentWithoutGossiper } = request;
+
+        console.log("Order is gossiped by the node it was signed for");
 
-        expect(gossiper !== nullGossiper).to.equal(true);
+        expect(gossiper).to.equal(requestGossiper);
+        // order id is the canonical order hash
+        expect(orderId).to.match(/^0x[0-9a-f]{64}$/);
         expect(expectedOrderCommitmentWithoutGossiper).to.deep.equal(orderCommitmentWithoutGossiper);
     })
 
@@ -47,7 +100,7 @@ describe('Network integration tests', function() {
         const client2 = await client(NODE2_PORT);
         const client3 = await client(NODE3_PORT);
 
-        await client2.CreateOrderCommitment(testOrders[1]);
+        await createOrder(NODE2_PORT, testOrders[1]);
 
         const orders1 = await client2.GetOrderCommitments({});
         const orders2 = await client2.GetOrderCommitments({});
@@ -65,13 +118,13 @@ describe('Network integration tests', function() {
         // add orders, just randomly choosing clients
         for (i = 0; i < testOrders.length; i++) {
             if (i % 3 === 0) {
-                await client1.CreateOrderCommitment(testOrders[i]);
+                await createOrder(NODE1_PORT, testOrders[i]);
             }
             else if (i % 3 === 1) {
-                await client2.CreateOrderCommitment(testOrders[i]);
+                await createOrder(NODE2_PORT, testOrders[i]);
             }
             else {
-                await client3.CreateOrderCommitment(testOrders[i]);
+                await createOrder(NODE3_PORT, testOrders[i]);
             }
         }
 
@@ -85,17 +138,66 @@ describe('Network integration tests', function() {
         expect(orders1.orderCommitments.length).to.deep.equal(testOrders.length);
     })
 
+    it("Should fetch a single order and orders of a token", async function() {
+        const client1 = await client(NODE1_PORT);
+        const client2 = await client(NODE2_PORT);
+
+        const orderCommitment = await createOrder(NODE1_PORT, testOrders[0]);
+
+        const fetched = await client2.GetOrderCommitment({ orderId: orderCommitment.orderId });
+        expect(fetched).to.deep.equal(orderCommitment);
+
+        const tokenOrders = await client2.GetOrdersForToken({
+            contractAddress: orderCommitment.contractAddress,
+            nftId: orderCommitment.nftId,
+        });
+        expect(tokenOrders.orderCommitments).to.deep.include(orderCommitment);
+
+        const missingId = "0x" + "0".repeat(64);
+        await client2.GetOrderCommitment({ orderId: missingId })
+            .then(() => expect.fail("missing order should not be found"))
+            .catch(e => expect(e.code).to.equal(5)); // NOT_FOUND
+    })
+
     it("should cancel an order commitment", async function() {
         const client1 = await client(NODE1_PORT);
 
-        const orderCommitment = await client1.CreateOrderCommitment(testOrders[0]);
+        const orderCommitment = await createOrder(NODE1_PORT, testOrders[0]);
         
         const orderCommitmentsBefore = await client1.GetOrderCommitments({});
 
-        await client1.CancelOrderCommitment(orderCommitment);
+        // cancel signed by someone else is rejected
+        const forged = signCancel(orderCommitment.orderId, '0x' + '1'.repeat(64));
+        await client1.CancelOrderCommitment({ orderId: orderCommitment.orderId, signature: forged })
+            .then(() => expect.fail("forged cancel should be rejected"))
+            .catch(e => expect(e.code).to.equal(7)); // PERMISSION_DENIED
+
+        await client1.CancelOrderCommitment({
+            orderId: orderCommitment.orderId,
+            signature: signCancel(orderCommitment.orderId, SIGNER_PRIVATE_KEY),
+        });
 
         const orderCommitmentsAfter = await client1.GetOrderCommitments({});
         
         expect(orderCommitmentsBefore.orderCommitments.length).to.deep.equal(orderCommitmentsAfter.orderCommitments.length + 1);
     })
-})
\ No newline at end of file
+
+    it("Late joining node syncs all open orders", async function() {
+        const client1 = await client(NODE1_PORT);
+
+        // node04 joins after the orders were gossiped
+        // detached, so the whole process group can be killed afterwards
+        const node4 = spawn('make', ['node04'], { cwd: path.resolve(__dirname, '../..'), detached: true });
+        try {
+            await new Promise(resolve => setTimeout(resolve, 15000));
+            const client4 = await client(NODE4_PORT);
+
+            const orders1 = await client1.GetOrderCommitments({});
+            const orders4 = await client4.GetOrderCommitments({});
+
+            expect(orders4).to.deep.equal(orders1);
+        } finally {
+            process.kill(-node4.pid);
+        }
+    })
+})
@@ -122,8 +122,8 @@ function signedTypeData(provider, from, data) {
     });
 }
 
-function Order(signer, taker, contractAddress, tokenAddress, nftId, gossiper, price, orderType) {
-	return { signer, taker, contractAddress, tokenAddress, nftId, gossiper, price, orderType };
+function Order(signer, taker, contractAddress, tokenAddress, nftId, gossiper, price, orderType, expiresAt = 0, nonce = 0, salt = 0) {
+	return { signer, taker, contractAddress, tokenAddress, nftId, gossiper, price, orderType, expiresAt, nonce, salt };
 }
 
 const Types = {
@@ -136,6 +136,9 @@ const Types = {
 		{name: 'gossiper', type: 'address'},
 		{name: 'price', type: 'uint128'},
 		{name: 'orderType', type: 'u8'},
+		{name: 'expiresAt', type: 'uint64'},
+		{name: 'nonce', type: 'uint64'},
+		{name: 'salt', type: 'uint64'},
 	]
 };
 
@@ -0,0 +1,53 @@
+const { ethers } = require('ethers');
+
+// Encode the signature for the node
+function toSignature(signature) {
+    return {
+        v: Buffer.from([signature.v]),
+        r: Buffer.from(signature.r.substring(2), 'hex'),
+        s: Buffer.from(signature.s.substring(2), 'hex'),
+    };
+}
+
+// Same as `Marketplace.hashForSignature`, i.e
+// keccak256 of abi.encode of all the order fields
+function hashForSignature(order) {
+    return ethers.utils.keccak256(
+        ethers.utils.defaultAbiCoder.encode(
+            ["address", "address", "address", "address", "uint128", "address", "uint128", "uint8", "uint64", "uint64", "uint64"],
+            [
+                order.signer,
+                order.taker,
+                order.contractAddress,
+                order.tokenAddress,
+                order.nftId,
+                order.gossiper,
+                order.price,
+                order.orderType,
+                order.expiresAt || 0,
+                order.nonce || 0,
+                order.salt || 0,
+            ]
+        )
+    );
+}
+
+// Sign the order by its signer, with the gossiping node filled in,
+// since the node verifies the signature over its own address
+function signOrder(order, gossiper, privateKey) {
+    const signed = { ...order, gossiper };
+    const signature = new ethers.utils.SigningKey(privateKey).signDigest(hashForSignature(signed));
+    return { ...signed, signature: toSignature(signature) };
+}
+
+// Sign a cancel of the order, i.e
+// keccak256(abi.encode(keccak256("CancelOrder(bytes32 orderHash)"), orderHash))
+function signCancel(orderId, privateKey) {
+    const typeHash = ethers.utils.id("CancelOrder(bytes32 orderHash)");
+    const digest = ethers.utils.keccak256(
+        ethers.utils.defaultAbiCoder.encode(["bytes32", "bytes32"], [typeHash, orderId])
+    );
+    return toSignature(new ethers.utils.SigningKey(privateKey).signDigest(digest));
+}
+
+module.exports = { hashForSignature, signOrder, signCancel };
@@ -12,7 +12,7 @@ async function main() {
         tokenAddress: "0x01", 
         nftId: "dasdasd", 
         gossiper: "dasdasddasd", 
-        price: 100000,
+        price: "100000",
         orderType: 1,
         orderId: "1231a"
     });
@@ -293,32 +293,76 @@ export class Marketplace extends React.Component {
     this.setState({ balance });
   }
 
-  // register new node
+  // Same as `Marketplace.hashForSignature`, i.e
+  // keccak256 of abi.encode of all the order fields
+  _hashForSignature(order) {
+    return ethers.utils.keccak256(
+      ethers.utils.defaultAbiCoder.encode(
+        ["address", "address", "address", "address", "uint128", "address", "uint128", "uint8", "uint64", "uint64", "uint64"],
+        [
+          order.signer,
+          order.taker,
+          order.contractAddress,
+          order.tokenAddress,
+          order.nftId,
+          order.gossiper,
+          order.price,
+          order.orderType,
+          order.expiresAt,
+          order.nonce,
+          order.salt,
+        ]
+      )
+    );
+  }
+
+  // sign new order commitment and send it to the current validator
   async _createOrderCommitment(orderCommitment) {
-    try{
-      const tx = await this._auction.registerValidator(
-        this.state.selectedAddress
-      );
-      this.setState({ txBeingSent: tx.hash });
-      
+    try {
       this._dismissTransactionError();
-      
-      const receipt = await tx.wait();
 
-      // The receipt, contains a status flag, which is 0 to indicate an error.
-      if (receipt.status === 0) {
-        // We can't know the exact error that made the transaction fail when it
-        // was mined, so we throw this generic one.
-        throw new Error("Transaction failed");
+      if (!this.state.validator) {
+        throw new Error("Current validator is not known yet");
       }
 
-      // If we got here, the transaction was successful, so you may want to
-      // update your state. Here, we update the user's balance.
-      await this._updateSlotState();
+      // the node verifies the signature with its own address as the gossiper
+      const order = {
+        taker: ethers.constants.AddressZero,
+        expiresAt: 0,
+        nonce: 0,
+        salt: ethers.BigNumber.from(ethers.utils.randomBytes(8)).toString(),
+        ...orderCommitment,
+        signer: this.state.selectedAddress,
+        gossiper: this.state.validator,
+      };
+
+      // the Marketplace recovers the signer from the hash itself,
+      // so it's signed without the message prefix
+      const signature = ethers.utils.splitSignature(
+        await this._provider.send("eth_sign", [this.state.selectedAddress, this._hashForSignature(order)])
+      );
+      order.signature = {
+        v: ethers.utils.base64.encode([signature.v]),
+        r: ethers.utils.base64.encode(signature.r),
+        s: ethers.utils.base64.encode(signature.s),
+      };
+
+      const response = await fetch(`/${this.state.grpcPort}/create`, {
+        method: "POST",
+        headers: { "Content-Type": "application/json" },
+        body: JSON.stringify(order),
+      });
+      const created = await response.json();
+
+      // the server answers with an empty object if the node rejected the order
+      if (!created.orderId) {
+        throw new Error("Order commitment was rejected by the node");
+      }
 
+      await this._updateMarketplace();
     } catch (error) {
       // We check the error code to see if this error was produced because the
-      // user rejected a tx. If that's the case, we do nothing.
+      // user rejected the signature. If that's the case, we do nothing.
       if (error.code === ERROR_CODE_TX_REJECTED_BY_USER) {
         return;
       }
@@ -327,12 +371,9 @@ export class Marketplace extends React.Component {
       // show them to the user, and for debugging.
       console.error(error);
       this.setState({ transactionError: error });
-    } finally {
-      // If we leave the try/catch, we aren't sending a tx anymore, so we clear
-      // this part of the state.
-      this.setState({ txBeingSent: undefined });
     }
   }
+
   // This method sends an ethereum transaction to bid for slot
   async _purchaseOrder(slotNumber, amount, bidAmount) {
     try {
@@ -10,6 +10,7 @@ use std::{
 };
 
 use common_types::{Address};
+use libp2p::Multiaddr;
 
 /// Gets the home directory of the current user
 pub fn get_home_dir() -> String {
@@ -22,7 +23,9 @@ pub struct Config {
     pub private_key: String,
     pub g_rpc_port: String,
     pub sync: bool,
-    pub bootnode: Option<String>,
+    pub bootnodes: Vec<Multiaddr>,
+    pub listen_addresses: Vec<Multiaddr>,
+    pub external_address: Option<Multiaddr>,
     pub eth_remote_url: String,
     pub auction_address: Address,
     pub marketplace_address: Address,
@@ -35,7 +38,9 @@ impl Default for Config {
             private_key: "0x0000000000000000000000000000000000000000000000000000000000000000".to_string(),
             g_rpc_port: "50051".to_string(),
             sync: false,
-            bootnode: None,
+            bootnodes: vec![],
+            listen_addresses: vec![],
+            external_address: None,
             // default Ganache port
             eth_remote_url: "http://127.0.0.1:8545".to_string(),
             auction_address: Address::zero(),
@@ -48,6 +53,34 @@ impl Default for Config {
 pub struct Cli {
     #[structopt(flatten)]
     pub options: CliOptions,
+    #[structopt(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(StructOpt, Debug)]
+pub enum Command {
+    /// Manage the libp2p key of the node given by `--node-id`
+    Key(KeyCommand),
+}
+
+#[derive(StructOpt, Debug)]
+pub enum KeyCommand {
+    /// Generate a new node key
+    Generate {
+        #[structopt(long, help = "Replace the existing key")]
+        force: bool,
+    },
+    /// Import hex encoded ed25519 secret key
+    Import {
+        secret: String,
+        #[structopt(long, help = "Replace the existing key")]
+        force: bool,
+    },
+    /// Show the peer id of the node key
+    Show {
+        #[structopt(long, help = "Also print the secret key")]
+        secret: bool,
+    },
 }
 
 #[derive(StructOpt, Debug)]
@@ -55,13 +88,17 @@ pub struct CliOptions {
     #[structopt(short, long, help = "Given name for the node")]
     pub node_id: Option<String>,
     #[structopt(short, long, help = "Private key associated with the node")]
-    pub private_key: String,
+    pub private_key: Option<String>,
     #[structopt(short, long, help = "Port for gRPC server", )]
     pub g_rpc_port: Option<String>,
     #[structopt(short, long, help = "Sync with other nodes")]
     pub sync: bool,
-    #[structopt(short, long, help = "Bootnode address")]
-    pub bootnode: Option<String>,
+    #[structopt(short, long, help = "Bootnode multiaddr /ip4/<ip>/tcp/<port>/p2p/<peer id>, can be repeated")]
+    pub bootnode: Vec<String>,
+    #[structopt(short, long, help = "Listen multiaddr e.g /ip4/0.0.0.0/tcp/4001 or /ip4/0.0.0.0/tcp/4002/ws, can be repeated")]
+    pub listen: Vec<String>,
+    #[structopt(short = "x", long, help = "Publicly reachable multiaddr announced to peers and registered on-chain")]
+    pub external_address: Option<String>,
     #[structopt(short, long, help = "Ethereum remote URL")]
     pub eth_remote_url: Option<String>,
     #[structopt(short, long, help = "Auction address")]
@@ -78,13 +115,32 @@ impl CliOptions {
             config.node_id = node_id.to_string();
         }
 
-        config.private_key = self.private_key.clone();
+        config.private_key = self.private_key.clone().ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, "private key is required")
+        })?;
 
         if let Some(g_rpc_port) = &self.g_rpc_port {
             config.g_rpc_port = g_rpc_port.to_string();
         }
         config.sync = self.sync;
-        config.bootnode = self.bootnode.clone();
+        for bootnode in &self.bootnode {
+            let address = bootnode.parse::<Multiaddr>().map_err(|e| {
+                io::Error::new(io::ErrorKind::InvalidInput, format!("invalid bootnode {}: {}", bootnode, e))
+            })?;
+            config.bootnodes.push(address);
+        }
+        for listen in &self.listen {
+            let address = listen.parse::<Multiaddr>().map_err(|e| {
+                io::Error::new(io::ErrorKind::InvalidInput, format!("invalid listen address {}: {}", listen, e))
+            })?;
+            config.listen_addresses.push(address);
+        }
+        if let Some(external_address) = &self.external_address {
+            let address = external_address.parse::<Multiaddr>().map_err(|e| {
+                io::Error::new(io::ErrorKind::InvalidInput, format!("invalid external address {}: {}", external_address, e))
+            })?;
+            config.external_address = Some(address);
+        }
 
         if let Some(eth_remote_url) = &self.eth_remote_url {
             config.eth_remote_url = eth_remote_url.to_string();
@@ -0,0 +1,106 @@
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+
+use common_types::{PeerId, hex};
+use libp2p::identity::{self, Keypair};
+
+use crate::cli::{get_home_dir, KeyCommand};
+
+/// Path of the libp2p key of the node in the data directory
+pub fn key_path(node_id: &str) -> PathBuf {
+    PathBuf::from(format!("{}/.nft-node/keys/{}/node_key", get_home_dir(), node_id))
+}
+
+/// Parse hex encoded ed25519 secret key
+pub fn parse_secret(secret: &str) -> io::Result<Keypair> {
+    let secret = secret.trim();
+    let bytes = hex::decode(secret.strip_prefix("0x").unwrap_or(secret))
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "node key is not hex encoded"))?;
+    let secret = identity::ed25519::SecretKey::from_bytes(bytes)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("invalid node key: {}", e)))?;
+    Ok(Keypair::Ed25519(secret.into()))
+}
+
+/// Hex encoded ed25519 secret key
+fn encode_secret(local_key: &Keypair) -> io::Result<String> {
+    match local_key {
+        Keypair::Ed25519(keypair) => Ok(hex::encode(keypair.secret())),
+        _ => Err(io::Error::new(io::ErrorKind::InvalidInput, "only ed25519 node keys are supported")),
+    }
+}
+
+/// Load the node key, `None` if it wasn't created yet
+pub fn load_key(node_id: &str) -> io::Result<Option<Keypair>> {
+    match fs::read_to_string(key_path(node_id)) {
+        Ok(secret) => parse_secret(&secret).map(Some),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// Store the node key, readable only by the owner
+pub fn save_key(node_id: &str, local_key: &Keypair) -> io::Result<()> {
+    let path = key_path(node_id);
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    fs::write(&path, encode_secret(local_key)?)?;
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o600))?;
+    }
+    Ok(())
+}
+
+/// Load the node key, generating and storing it on the first launch
+pub fn load_or_generate(node_id: &str) -> io::Result<Keypair> {
+    if let Some(local_key) = load_key(node_id)? {
+        return Ok(local_key);
+    }
+    let local_key = Keypair::generate_ed25519();
+    save_key(node_id, &local_key)?;
+    println!("[NODE] Generated node key at {}", key_path(node_id).display());
+    Ok(local_key)
+}
+
+/// Refuse to replace the existing key unless forced,
+/// since the PeerId is part of the node URL registered on-chain
+fn replace_key(node_id: &str, local_key: &Keypair, force: bool) -> io::Result<()> {
+    if !force && load_key(node_id)?.is_some() {
+        return Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!("node key already exists at {}, use --force to replace it", key_path(node_id).display()),
+        ));
+    }
+    save_key(node_id, local_key)
+}
+
+/// Execute the `key` subcommand for the node
+pub fn run_key_command(command: KeyCommand, node_id: &str) -> io::Result<()> {
+    match command {
+        KeyCommand::Generate { force } => {
+            let local_key = Keypair::generate_ed25519();
+            replace_key(node_id, &local_key, force)?;
+            println!("Generated node key, peer id: {}", PeerId::from(local_key.public()));
+        },
+        KeyCommand::Import { secret, force } => {
+            let local_key = parse_secret(&secret)?;
+            replace_key(node_id, &local_key, force)?;
+            println!("Imported node key, peer id: {}", PeerId::from(local_key.public()));
+        },
+        KeyCommand::Show { secret } => {
+            let local_key = load_key(node_id)?.ok_or_else(|| {
+                io::Error::new(io::ErrorKind::NotFound, format!("no node key at {}", key_path(node_id).display()))
+            })?;
+            println!("Key path: {}", key_path(node_id).display());
+            println!("Peer id: {}", PeerId::from(local_key.public()));
+            if secret {
+                println!("Secret key: {}", encode_secret(&local_key)?);
+            }
+        },
+    }
+    Ok(())
+}
@@ -1,13 +1,17 @@
 mod utils;
 mod cli;
+mod keys;
 
-use async_std::task;
-use cli::{Cli, Config};
+use async_std::{task, stream};
+use async_std::prelude::*;
+use cli::{Cli, Command, Config};
 use db::rocks::RocksDB;
 use grpc::start_g_rpc;
-use libp2p::identity;
 use structopt::StructOpt;
-use common_types::{PeerId, node::{P2pNode, NodeType}, LocalWallet, Signer};
+use common_types::{
+    PeerId, node::{P2pNode, NodeType}, LocalWallet, Signer,
+    AppStorage, NetworkEvent, NetworkEvents, ORDER_PRUNE_INTERVAL, ORDER_EVENT_RETENTION, order, handshake
+};
 use std::sync::Arc;
 use p2p_service::P2pService;
 use web3::{Web3};
@@ -16,20 +20,27 @@ use crate::cli::get_home_dir;
 
 #[async_std::main]
 async fn main() {
-    let Cli {options} = Cli::from_args();
+    let Cli {options, command} = Cli::from_args();
+
+    if let Some(Command::Key(key_command)) = command {
+        let node_id = options.node_id.unwrap_or_else(|| Config::default().node_id);
+        if let Err(e) = keys::run_key_command(key_command, &node_id) {
+            println!("[NODE] {}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
     
     match options.to_config() {
-        // if user has supplied arguments, we launch the node from configuration
         Ok(config) => {
             println!("Launching node with config: {:?}", config);
             let p2p_node = from_config(config);
             run(p2p_node).await;
         },
-        // otherwise we launch node with default configuration
+        // e.g a malformed listen or external address
         Err(e) => {
-            println!("Launching node with default configuration...");
-            let p2p_node = P2pNode::default();
-            run(p2p_node).await;
+            println!("[NODE] Invalid configuration: {}", e);
+            std::process::exit(1);
         }
     }
 }
@@ -47,12 +58,22 @@ pub async fn run(node: P2pNode) {
     
     let db = Arc::new(db);
 
-    let service = P2pService::new(node.local_key.clone(), db.clone(), None);
+    // proves to peers that the peer id belongs to the node wallet
+    let handshake = handshake::sign_handshake(&node.wallet, &node.peer_id)
+        .await
+        .expect("Failed to sign handshake");
+
+    let service = P2pService::new(&node, db.clone(), handshake).unwrap_or_else(|e| {
+        println!("[NODE] Couldn't start the p2p service: {}", e);
+        std::process::exit(1);
+    });
 
-    let network_receiver = service.network_receiver();
     let network_sender = service.network_sender();
+    let network_events = service.network_events();
 
     let g_rpc_endpoint = format!("127.0.0.1:{}", &node.g_port);
+    // advertised address registered in the auction protocol
+    let node_url = node.node_url(&g_rpc_endpoint);
     let peer_id = node.peer_id.clone();
 
     // Chain related values
@@ -64,6 +85,9 @@ pub async fn run(node: P2pNode) {
     // public address
     let local_wallet_public = node_wallet.address();
 
+    let prune_db = db.clone();
+    let prune_events = network_events.clone();
+
     let node = Arc::new(node);
     let p2p = task::spawn(async {
         service.launch(node).await;
@@ -78,7 +102,7 @@ pub async fn run(node: P2pNode) {
         marketplace,
         None,
         db.clone(),
-        g_rpc_endpoint.clone(),
+        node_url,
     ).await;
 
     let g_rpc = task::spawn(async move {
@@ -88,6 +112,7 @@ pub async fn run(node: P2pNode) {
             peer_id, 
             Arc::clone(&db), 
             network_sender.clone(), 
+            network_events,
             &g_rpc_endpoint, 
             local_wallet_public
         ).await
@@ -99,19 +124,50 @@ pub async fn run(node: P2pNode) {
         web3_service.launch_subscriptions().await
     });
 
+    // periodically removes expired orders from the storage
+    let pruner = task::spawn(prune_expired_orders(prune_db, prune_events));
+
     utils::block_until_sigint().await;
 
     p2p.cancel().await;
     g_rpc.cancel().await;
     web3_task.cancel().await;
+    pruner.cancel().await;
+}
+
+/// Prunes expired order commitments and old order events from the storage
+async fn prune_expired_orders(db: Arc<RocksDB>, events: NetworkEvents) {
+    let mut interval = stream::interval(ORDER_PRUNE_INTERVAL);
+    while interval.next().await.is_some() {
+        match db.prune_expired_orders(order::unix_now()) {
+            Ok(pruned) if !pruned.is_empty() => {
+                println!("[NODE] Pruned {} expired orders", pruned.len());
+                events.publish(NetworkEvent::OrdersExpired { hashes: pruned });
+            },
+            Ok(_) => {},
+            Err(e) => {
+                println!("[NODE] Error pruning expired orders: {}", e);
+            }
+        }
+
+        if let Err(e) = db.prune_tombstones(order::unix_now()) {
+            println!("[NODE] Error pruning tombstones: {}", e);
+        }
+
+        let events_before = order::unix_now().saturating_sub(ORDER_EVENT_RETENTION.as_secs());
+        if let Err(e) = db.prune_order_events(events_before) {
+            println!("[NODE] Error pruning order events: {}", e);
+        }
+    }
 }
 
 
 /// New node from configuration
 /// By default every node is a full node
 pub fn from_config(config: Config) -> P2pNode {
-    // generate local keys and peer id
-    let local_key = identity::Keypair::generate_ed25519();
+    // the key is persisted, so the peer id in the on-chain node URL stays the same
+    let local_key = keys::load_or_generate(&config.node_id)
+        .expect("Failed to load node key");
     let local_peer_id = PeerId::from_public_key(local_key.public());
 
     println!("[NODE] Local peer id: {:?}", local_peer_id);
@@ -134,5 +190,10 @@ pub fn from_config(config: Config) -> P2pNode {
         eth_remote_url: config.eth_remote_url,
         auction_address: config.auction_address,
         marketplace_address: config.marketplace_address,
+        sync: config.sync,
+        bootnodes: config.bootnodes,
+        listen_addresses: config.listen_addresses,
+        external_address: config.external_address,
+        agent_version: concat!("nft-node/", env!("CARGO_PKG_VERSION")).to_string(),
     }
 }
\ No newline at end of file
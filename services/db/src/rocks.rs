@@ -1,13 +1,22 @@
-use std::{path::Iter, borrow::Cow, collections::hash_set};
+use std::borrow::Cow;
+use std::convert::TryFrom;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
 
-use common_types::{IteratorMode, store::RecordStore, Key, Record, ProviderRecord, PeerId};
-pub use rocksdb::{Options, WriteBatch, DB, DBIterator};
-pub use common_types::{ Storage, Error, AppStorage};
-use std::collections::{hash_map};
+use common_types::{
+    IteratorMode, Direction, store::{self, RecordStore}, Key, Record, ProviderRecord,
+    PeerId, Multiaddr, order, query, p2p::{StoredRecord, StoredProvider},
+};
+use prost::Message;
+pub use rocksdb::{Options, WriteBatch, DB, DBIterator, ColumnFamily};
+pub use common_types::{ Storage, Error, AppStorage, Column, Batch, BatchOp};
 /// RocksDB instance
 #[derive(Debug)]
 pub struct RocksDB {
     pub db: DB,
+    /// Sequence number of the next order event
+    next_event: Mutex<u64>,
 }
 
 impl RocksDB {
@@ -17,109 +26,341 @@ impl RocksDB {
     {
         let mut opts = Options::default();
         opts.create_if_missing(true);
-        let db = DB::open(&opts, path)?;
-        Ok(RocksDB { db })
+        opts.create_missing_column_families(true);
+        let columns = Column::ALL.iter().map(|column| column.name());
+        let db = DB::open_cf(&opts, path, columns)?;
+
+        let next_event = {
+            let events = db.cf_handle(Column::OrderEvents.name()).expect("column families are created on open");
+            db.iterator_cf(events, IteratorMode::End)
+                .next()
+                .and_then(|(key, _)| query::event_sequence(&key))
+                .map_or(0, |sequence| sequence + 1)
+        };
+        Ok(RocksDB { db, next_event: Mutex::new(next_event) })
+    }
+
+    /// Handle of the column family
+    fn cf(&self, column: Column) -> &ColumnFamily {
+        self.db
+            .cf_handle(column.name())
+            .expect("column families are created on open")
     }
 }
 
 /// Implement Storage trait for RocksDB
 impl Storage for RocksDB {
     /// Write in the storage
-    fn write<K, V>(&self, key: K, value: V) -> Result<(), Error>
+    fn write<K, V>(&self, column: Column, key: K, value: V) -> Result<(), Error>
     where
         K: AsRef<[u8]>,
         V: AsRef<[u8]>,
     {
-        Ok(self.db.put(key, value)?)
+        Ok(self.db.put_cf(self.cf(column), key, value)?)
     }
 
     /// Read from the storage
-    fn read<K>(&self, key: K) -> Result<Option<Vec<u8>>, Error>
+    fn read<K>(&self, column: Column, key: K) -> Result<Option<Vec<u8>>, Error>
     where
         K: AsRef<[u8]>,
     {
-        Ok(self.db.get(key)?)
+        Ok(self.db.get_cf(self.cf(column), key)?)
     }
 
     // Delete from the storage
-    fn delete<K>(&self, key: K) -> Result<(), Error>
+    fn delete<K>(&self, column: Column, key: K) -> Result<(), Error>
     where
         K: AsRef<[u8]>,
     {
-        Ok(self.db.delete(key)?)
+        Ok(self.db.delete_cf(self.cf(column), key)?)
     }
 
     /// Check if the key exists in the storage
-    fn contains<K>(&self, key: K) -> Result<bool, Error>
+    fn contains<K>(&self, column: Column, key: K) -> Result<bool, Error>
     where
         K: AsRef<[u8]>,
     {
-        Ok(self.db.get(key)?.is_some())
+        Ok(self.db.get_cf(self.cf(column), key)?.is_some())
     }
 
-    fn iterator(&self, mode: IteratorMode) -> DBIterator
+    fn iterator(&self, column: Column, mode: IteratorMode) -> DBIterator
     {
-        self.db.iterator(mode)
+        self.db.iterator_cf(self.cf(column), mode)
+    }
+
+    /// Apply the batch with a single `WriteBatch`.
+    /// Order events get their sequence numbers under the lock, so they're stored in commit order
+    fn commit(&self, batch: Batch) -> Result<(), Error> {
+        let mut write_batch = WriteBatch::default();
+        for op in batch.ops {
+            match op {
+                BatchOp::Put(column, key, value) => write_batch.put_cf(self.cf(column), key, value),
+                BatchOp::Delete(column, key) => write_batch.delete_cf(self.cf(column), key),
+            }
+        }
+
+        let mut next_event = self.next_event.lock().unwrap();
+        let mut sequence = *next_event;
+        for event in batch.events {
+            write_batch.put_cf(self.cf(Column::OrderEvents), query::event_key(sequence), event);
+            sequence += 1;
+        }
+        self.db.write(write_batch)?;
+        *next_event = sequence;
+        Ok(())
     }
 }
 
 impl AppStorage for RocksDB {}
 
-// impl<'a> RecordStore<'a> for RocksDB {
-//     type RecordsIter = std::iter::Map<
-//         hash_map::Values<'a, Key, Record>,
-//         fn(&'a Record) -> Cow<'a, Record>,
-//     >;
-
-//     type ProvidedIter = std::iter::Map<
-//         hash_set::Iter<'a, ProviderRecord>,
-//         fn(&'a ProviderRecord) -> Cow<'a, ProviderRecord>,
-//     >;
-
-//     fn get(&'a self, key: &Key) -> Option<Cow<'_, Record>> {
-//         match self.db.get(key) {
-//             Ok(Some(value)) => Some(Cow::Owned(Record::new(key.clone(), value))),
-//             _ => None,
-//         }
-//     }
-
-//     fn put(&'a mut self, record: Record) -> Result<(), common_types::store::Error> {
-//         match self.db.put(record.key, record.value) {
-//             Ok(_) => Ok(()),
-//             // To-do: solve this issue, i.e converting error to common_types::store::Error
-//             Err(e) => Err(common_types::store::Error::MaxRecords),
-//         }
-//     }
-
-//     fn remove(&'a mut self, k: &Key){
-//         match self.db.delete(k) {
-//             Ok(_) => (),
-//             // To-do: solve this issue, i.e converting error to common_types::store::Error
-//             Err(e) => (),
-//         }
-//     }
-
-//     fn add_provider(&'a mut self, record: ProviderRecord) -> common_types::store::Result<()> {
-//         unimplemented!()
-//     }
-
-//     fn remove_provider(&'a mut self, k: &Key, provider: &PeerId){
-//         unimplemented!()
-//     }
-
-//     fn providers(&'a self, key: &Key) -> Vec<ProviderRecord> {
-//         unimplemented!()
-//     }
-
-//     fn records(&'a self) -> Self::RecordsIter {
-//         let mut hashmap = hash_map::HashMap::new();    
-//         self.iterator(IteratorMode::Start).map(|(k, v)| {
-//             hashmap.insert(Key::new(&k), Record::new(Key::new(&k), v.into()));
-//         });
-//         hashmap.values().map(|record| Cow::Owned(record)).to_owned()
-//     }
-
-//     fn provided(&'a self) -> Self::ProvidedIter {
-//         unimplemented!()
-//     }
-// }
\ No newline at end of file
+/// Maximum number of stored records
+const MAX_RECORDS: usize = 1024;
+/// Maximum size of the record value
+const MAX_VALUE_BYTES: usize = 65 * 1024;
+/// Maximum number of providers of a key
+const MAX_PROVIDERS_PER_KEY: usize = 20;
+/// Maximum number of keys provided by the local node
+const MAX_PROVIDED_KEYS: usize = 1024;
+
+const RECORD_PREFIX: &[u8] = b"r";
+const PROVIDER_PREFIX: &[u8] = b"p";
+
+/// Kademlia `RecordStore` persisted in the `Kademlia` column family,
+/// so records like the slot number survive restarts
+#[derive(Debug, Clone)]
+pub struct RocksRecordStore {
+    db: Arc<RocksDB>,
+    local_id: PeerId,
+    /// Number of stored records, expired ones included until a scan deletes them
+    records: Arc<AtomicUsize>,
+}
+
+/// Key of the record: prefix ++ record key
+fn record_key(key: &Key) -> Vec<u8> {
+    [RECORD_PREFIX, key.as_ref()].concat()
+}
+
+/// Prefix of all providers of the key: prefix ++ key length ++ record key
+fn providers_prefix(key: &Key) -> Vec<u8> {
+    [PROVIDER_PREFIX, &(key.as_ref().len() as u32).to_be_bytes(), key.as_ref()].concat()
+}
+
+/// Key of the provider record: providers prefix ++ provider
+fn provider_key(key: &Key, provider: &PeerId) -> Vec<u8> {
+    [providers_prefix(key), provider.to_bytes()].concat()
+}
+
+/// Stored unix timestamp of the expiration instant
+fn to_unix(expires: Option<Instant>) -> u64 {
+    match expires {
+        Some(expires) => order::unix_now() + expires.saturating_duration_since(Instant::now()).as_secs(),
+        None => 0,
+    }
+}
+
+/// Expiration instant of the stored unix timestamp
+fn from_unix(expires_at: u64) -> Option<Instant> {
+    match expires_at {
+        0 => None,
+        expires_at => Some(Instant::now() + Duration::from_secs(expires_at.saturating_sub(order::unix_now()))),
+    }
+}
+
+fn is_expired(expires_at: u64, now: u64) -> bool {
+    expires_at != 0 && expires_at <= now
+}
+
+/// Kademlia has no error for storage failures, so they are logged and reported as a full store
+fn store_error(e: Error) -> store::Error {
+    println!("[KAD] Error accessing record store: {}", e);
+    store::Error::MaxRecords
+}
+
+impl RocksRecordStore {
+    pub fn new(db: Arc<RocksDB>, local_id: PeerId) -> Self {
+        let store = RocksRecordStore { db, local_id, records: Arc::new(AtomicUsize::new(0)) };
+        store.stored_records();
+        store
+    }
+
+    /// Decode the record, `None` if it's malformed
+    fn decode_record(stored: StoredRecord) -> Option<Record> {
+        let publisher = match stored.publisher.is_empty() {
+            true => None,
+            false => Some(PeerId::from_bytes(&stored.publisher).ok()?),
+        };
+        Some(Record {
+            key: Key::from(stored.key),
+            value: stored.value,
+            publisher,
+            expires: from_unix(stored.expires_at),
+        })
+    }
+
+    /// Decode the provider record, `None` if it's malformed
+    fn decode_provider(stored: StoredProvider) -> Option<ProviderRecord> {
+        Some(ProviderRecord {
+            key: Key::from(stored.key),
+            provider: PeerId::from_bytes(&stored.provider).ok()?,
+            expires: from_unix(stored.expires_at),
+            addresses: stored.addresses
+                .into_iter()
+                .filter_map(|address| Multiaddr::try_from(address).ok())
+                .collect(),
+        })
+    }
+
+    /// Decoded values of the column stored under the prefix,
+    /// expired and malformed ones are deleted in a single batch
+    fn scan<T: Message + Default>(&self, prefix: &[u8], expires_at: fn(&T) -> u64) -> Vec<(Box<[u8]>, T)> {
+        let now = order::unix_now();
+        let mut stale = Batch::default();
+        let live = self.db
+            .iterator(Column::Kademlia, IteratorMode::From(prefix, Direction::Forward))
+            .take_while(|(key, _)| key.starts_with(prefix))
+            .filter_map(|(key, value)| match T::decode(value.as_ref()) {
+                Ok(stored) if !is_expired(expires_at(&stored), now) => Some((key, stored)),
+                _ => {
+                    stale.delete(Column::Kademlia, &key);
+                    None
+                }
+            })
+            .collect();
+
+        if !stale.ops.is_empty() {
+            if let Err(e) = self.db.commit(stale) {
+                println!("[KAD] Error deleting expired records: {}", e);
+            }
+        }
+        live
+    }
+
+    /// Live records, the scan also resets the record count
+    fn stored_records(&self) -> Vec<(Box<[u8]>, StoredRecord)> {
+        let records = self.scan(RECORD_PREFIX, |stored: &StoredRecord| stored.expires_at);
+        self.records.store(records.len(), Ordering::SeqCst);
+        records
+    }
+
+    fn stored_providers(&self, key: &Key) -> Vec<(Box<[u8]>, StoredProvider)> {
+        self.scan(&providers_prefix(key), |stored: &StoredProvider| stored.expires_at)
+    }
+
+    /// Provider records of the local node
+    fn stored_provided(&self) -> Vec<(Box<[u8]>, StoredProvider)> {
+        let local_id = self.local_id.to_bytes();
+        self.scan(PROVIDER_PREFIX, |stored: &StoredProvider| stored.expires_at)
+            .into_iter()
+            .filter(|(_, stored)| stored.provider == local_id)
+            .collect()
+    }
+}
+
+impl<'a> RecordStore<'a> for RocksRecordStore {
+    type RecordsIter = std::vec::IntoIter<Cow<'a, Record>>;
+    type ProvidedIter = std::vec::IntoIter<Cow<'a, ProviderRecord>>;
+
+    fn get(&'a self, key: &Key) -> Option<Cow<'_, Record>> {
+        let stored = self.db.get::<StoredRecord>(Column::Kademlia, record_key(key)).ok()??;
+        if is_expired(stored.expires_at, order::unix_now()) {
+            return None;
+        }
+        Self::decode_record(stored).map(Cow::Owned)
+    }
+
+    fn put(&'a mut self, record: Record) -> store::Result<()> {
+        if record.value.len() >= MAX_VALUE_BYTES {
+            return Err(store::Error::ValueTooLarge);
+        }
+
+        let key = record_key(&record.key);
+        let exists = self.db.contains(Column::Kademlia, &key).map_err(store_error)?;
+        // only a full store is rescanned, to delete its expired records
+        if !exists
+            && self.records.load(Ordering::SeqCst) >= MAX_RECORDS
+            && self.stored_records().len() >= MAX_RECORDS
+        {
+            return Err(store::Error::MaxRecords);
+        }
+
+        let stored = StoredRecord {
+            key: record.key.to_vec(),
+            value: record.value,
+            publisher: record.publisher.map(|publisher| publisher.to_bytes()).unwrap_or_default(),
+            expires_at: to_unix(record.expires),
+        };
+        self.db.put(Column::Kademlia, key, &stored).map_err(store_error)?;
+        if !exists {
+            self.records.fetch_add(1, Ordering::SeqCst);
+        }
+        Ok(())
+    }
+
+    fn remove(&'a mut self, key: &Key) {
+        let key = record_key(key);
+        match self.db.contains(Column::Kademlia, &key) {
+            Ok(false) => {}
+            Ok(true) => match self.db.delete(Column::Kademlia, &key) {
+                Ok(()) => {
+                    self.records.fetch_sub(1, Ordering::SeqCst);
+                }
+                Err(e) => println!("[KAD] Error removing record: {}", e),
+            },
+            Err(e) => println!("[KAD] Error removing record: {}", e),
+        }
+    }
+
+    fn records(&'a self) -> Self::RecordsIter {
+        self.stored_records()
+            .into_iter()
+            .filter_map(|(_, stored)| Self::decode_record(stored))
+            .map(Cow::Owned)
+            .collect::<Vec<_>>()
+            .into_iter()
+    }
+
+    fn add_provider(&'a mut self, record: ProviderRecord) -> store::Result<()> {
+        let key = provider_key(&record.key, &record.provider);
+        let exists = self.db.contains(Column::Kademlia, &key).map_err(store_error)?;
+
+        if !exists {
+            if self.stored_providers(&record.key).len() >= MAX_PROVIDERS_PER_KEY {
+                // the key has enough providers already
+                return Ok(());
+            }
+            if record.provider == self.local_id && self.stored_provided().len() >= MAX_PROVIDED_KEYS {
+                return Err(store::Error::MaxProvidedKeys);
+            }
+        }
+
+        let stored = StoredProvider {
+            key: record.key.to_vec(),
+            provider: record.provider.to_bytes(),
+            expires_at: to_unix(record.expires),
+            addresses: record.addresses.iter().map(|address| address.to_vec()).collect(),
+        };
+        self.db.put(Column::Kademlia, key, &stored).map_err(store_error)
+    }
+
+    fn providers(&'a self, key: &Key) -> Vec<ProviderRecord> {
+        self.stored_providers(key)
+            .into_iter()
+            .filter_map(|(_, stored)| Self::decode_provider(stored))
+            .collect()
+    }
+
+    fn provided(&'a self) -> Self::ProvidedIter {
+        self.stored_provided()
+            .into_iter()
+            .filter_map(|(_, stored)| Self::decode_provider(stored))
+            .map(Cow::Owned)
+            .collect::<Vec<_>>()
+            .into_iter()
+    }
+
+    fn remove_provider(&'a mut self, key: &Key, provider: &PeerId) {
+        if let Err(e) = self.db.delete(Column::Kademlia, provider_key(key, provider)) {
+            println!("[KAD] Error removing provider record: {}", e);
+        }
+    }
+}
@@ -2,7 +2,12 @@ use std::io::{Cursor};
 use std::sync::Arc;
 use std::fmt::LowerHex;
 use std::error::Error;
+use std::pin::Pin;
+use std::str::FromStr;
 use async_std::channel::Sender;
+use async_std::task;
+use futures::{Stream, SinkExt, StreamExt};
+use futures::channel::{mpsc, oneshot};
 use libp2p::PeerId;
 use prost::Message;
 // use node::service::{DB};
@@ -12,8 +17,8 @@ use common_types::{
     AppStorage, EmptyRequest, 
     Error as DBError, NetworkMessage, 
     NodeRpc, NodeRpcServer, OrderCommitment, 
-    OrderCommitmentList, Storage, Address,
-    Uuid
+    OrderCommitmentList, OrderQuery, OrderSubscription, OrderEvent,
+    OrderId, TokenId, CancelOrder, PeerScore, PeerScoreList, PeerList, PingRequest, PingResponse, Storage, Address, Uuid, hex, order, query, NetworkEvents
 };
 
 /// Starts a gRPC server that listens on the specified port.
@@ -21,6 +26,7 @@ pub async fn start_g_rpc<DB: Storage> (
     peer_id: PeerId,
     db: Arc<DB>,
     network_sender: Sender<NetworkMessage>,
+    events: NetworkEvents,
     endpoint: &str,
     address: Address,
 ) -> Result<(), Box<dyn Error + Send + Sync>> 
@@ -28,7 +34,7 @@ pub async fn start_g_rpc<DB: Storage> (
         DB: AppStorage + Send + Sync + 'static
     {
         let addr = endpoint.parse().unwrap();
-        let service = GRPCService{peer_id, db, network_sender, address};
+        let service = GRPCService{peer_id, db, network_sender, events, address};
 
         println!("[GRPC] Ready on http://{}", addr);
 
@@ -48,29 +54,79 @@ pub struct GRPCService<DB> {
     peer_id: PeerId,
     pub address: Address,
     pub db: Arc<DB>,
-    network_sender: Sender<NetworkMessage>
+    network_sender: Sender<NetworkMessage>,
+    /// wakes order feed subscribers when the order book changes
+    events: NetworkEvents,
 }
 
 #[tonic::async_trait]
 impl<DB> NodeRpc for GRPCService<DB>
     where DB: AppStorage + Send + Sync + 'static
 {
-    async fn ping(&self, request: Request<EmptyRequest>) -> Result<Response<EmptyRequest>, Status> {
-        if self.network_sender.send(NetworkMessage::PingRequest{peer_id: PeerId::random()}).await.is_err() {
-            println!("[GRPC] Error sending ping request");
+    async fn ping(&self, request: Request<PingRequest>) -> Result<Response<PingResponse>, Status> {
+        let target = request.into_inner().peer_id;
+        let peer_id = match target.is_empty() {
+            true => None,
+            false => Some(PeerId::from_str(&target).map_err(|_| {
+                Status::new(tonic::Code::InvalidArgument, format!("[GRPC] Invalid peer id: {}", target))
+            })?),
         };
 
-        Ok(Response::new(EmptyRequest::default()))
+        let (sender, receiver) = oneshot::channel();
+        if self.network_sender.send(NetworkMessage::PingRequest { peer_id, sender }).await.is_err() {
+            return Err(Status::new(tonic::Code::Unavailable, "[GRPC] Network service is down"));
+        }
+
+        let results = receiver.await
+            .map_err(|_| Status::new(tonic::Code::Unavailable, "[GRPC] Network service is down"))?;
+
+        Ok(Response::new(PingResponse { results }))
     }
 
-    /// Get stored order commitments from the storage
-    async fn get_order_commitments(&self, request: Request<EmptyRequest>) -> Result<Response<OrderCommitmentList>, Status> {
-        let commitments = match self.db.retrieve_order_commitments() {
+    /// Get gossipsub scores of the connected peers
+    async fn get_peer_scores(&self, request: Request<EmptyRequest>) -> Result<Response<PeerScoreList>, Status> {
+        let (sender, receiver) = oneshot::channel();
+        if self.network_sender.send(NetworkMessage::PeerScores { sender }).await.is_err() {
+            return Err(Status::new(tonic::Code::Unavailable, "[GRPC] Network service is down"));
+        }
+
+        let scores = receiver.await
+            .map_err(|_| Status::new(tonic::Code::Unavailable, "[GRPC] Network service is down"))?;
+
+        let peer_scores = scores
+            .into_iter()
+            .map(|(peer_id, score)| PeerScore { peer_id: peer_id.to_string(), score })
+            .collect();
+
+        Ok(Response::new(PeerScoreList { peer_scores }))
+    }
+
+    /// Get connected peers of the node
+    async fn get_peers(&self, request: Request<EmptyRequest>) -> Result<Response<PeerList>, Status> {
+        let (sender, receiver) = oneshot::channel();
+        if self.network_sender.send(NetworkMessage::Peers { sender }).await.is_err() {
+            return Err(Status::new(tonic::Code::Unavailable, "[GRPC] Network service is down"));
+        }
+
+        let peers = receiver.await
+            .map_err(|_| Status::new(tonic::Code::Unavailable, "[GRPC] Network service is down"))?;
+
+        Ok(Response::new(PeerList { peers }))
+    }
+
+    /// Get a page of stored order commitments matching the filter
+    async fn get_order_commitments(&self, request: Request<OrderQuery>) -> Result<Response<OrderCommitmentList>, Status> {
+        let query = request.into_inner();
+        let commitments = match self.db.query_order_commitments(&query) {
             Ok(commitments) => commitments,
             Err(e) => {
                 println!("[GRPC] Error retrieving order commitments: {}", e);
+                let code = match *e {
+                    DBError::InvalidQuery(_) | DBError::InvalidOrder(_) => tonic::Code::InvalidArgument,
+                    _ => tonic::Code::Internal,
+                };
                 return Err(Status::new(
-                    tonic::Code::Internal,
+                    code,
                     format!("[GRPC] Error retrieving order commitments: {}", e)
                 ));
             }
@@ -79,41 +135,222 @@ impl<DB> NodeRpc for GRPCService<DB>
         Ok(Response::new(commitments))
     }
 
+    /// Get single order commitment by its id, i.e the order hash
+    async fn get_order_commitment(&self, request: Request<OrderId>) -> Result<Response<OrderCommitment>, Status> {
+        let order_id = request.into_inner().order_id;
+        let hash = order::parse_order_id(&order_id)
+            .map_err(|e| Status::new(tonic::Code::InvalidArgument, format!("[GRPC] {}", e)))?;
+
+        match self.db.order_commitment(&hash) {
+            Ok(Some(commitment)) if !order::is_expired(&commitment, order::unix_now()) => {
+                Ok(Response::new(commitment))
+            },
+            Ok(_) => Err(Status::new(
+                tonic::Code::NotFound,
+                format!("[GRPC] Order commitment not found: {}", order_id)
+            )),
+            Err(e) => {
+                println!("[GRPC] Error retrieving order commitment: {}", e);
+                Err(Status::new(
+                    tonic::Code::Internal,
+                    format!("[GRPC] Error retrieving order commitment: {}", e)
+                ))
+            }
+        }
+    }
+
+    /// Get all open order commitments for the NFT
+    async fn get_orders_for_token(&self, request: Request<TokenId>) -> Result<Response<OrderCommitmentList>, Status> {
+        let token = request.into_inner();
+        let contract = token.contract_address.parse::<Address>()
+            .map_err(|_| Status::new(
+                tonic::Code::InvalidArgument,
+                format!("[GRPC] Invalid contract address: {}", token.contract_address)
+            ))?;
+        let nft_id = order::parse_uint128("nft id", &token.nft_id)
+            .map_err(|e| Status::new(tonic::Code::InvalidArgument, format!("[GRPC] {}", e)))?;
+
+        match self.db.orders_for_token(contract, nft_id) {
+            Ok(commitments) => Ok(Response::new(commitments)),
+            Err(e) => {
+                println!("[GRPC] Error retrieving order commitments: {}", e);
+                Err(Status::new(
+                    tonic::Code::Internal,
+                    format!("[GRPC] Error retrieving order commitments: {}", e)
+                ))
+            }
+        }
+    }
+
+    type SubscribeOrdersStream = Pin<Box<dyn Stream<Item = Result<OrderEvent, Status>> + Send + 'static>>;
+
+    /// Stream order book changes matching the filter.
+    /// Starts after the event with the given cursor, or with the next event if it's empty
+    async fn subscribe_orders(&self, request: Request<OrderSubscription>) -> Result<Response<Self::SubscribeOrdersStream>, Status> {
+        let subscription = request.into_inner();
+
+        let selector = query::OrderSelector::parse(subscription.filter.as_ref())
+            .map_err(|e| Status::new(
+                tonic::Code::InvalidArgument,
+                format!("[GRPC] Invalid order filter: {}", e)
+            ))?;
+
+        let mut cursor = if subscription.cursor.is_empty() {
+            self.db.latest_order_event_key().unwrap_or_default()
+        } else {
+            hex::decode(&subscription.cursor).map_err(|_| Status::new(
+                tonic::Code::InvalidArgument,
+                format!("[GRPC] Invalid cursor: {}", subscription.cursor)
+            ))?
+        };
+
+        let (mut sender, receiver) = mpsc::channel(query::DEFAULT_PAGE_SIZE);
+        let db = self.db.clone();
+        // subscribed before the first read, so no change is missed in between
+        let mut changes = self.events.subscribe();
+
+        // tail the order feed until the client goes away
+        task::spawn(async move {
+            while !sender.is_closed() {
+                let events = match db.order_events_after(&cursor, query::DEFAULT_PAGE_SIZE) {
+                    Ok(events) => events,
+                    Err(e) => {
+                        let _ = sender.send(Err(Status::new(
+                            tonic::Code::Internal,
+                            format!("[GRPC] Error reading order events: {}", e)
+                        ))).await;
+                        break;
+                    }
+                };
+
+                // wait for the p2p service to change the order book
+                if events.is_empty() {
+                    loop {
+                        match changes.next().await {
+                            Some(event) if event.changes_orders() => break,
+                            Some(_) => continue,
+                            None => return,
+                        }
+                    }
+                    continue;
+                }
+
+                for (key, mut event) in events {
+                    cursor = key;
+                    let matches = event.order_commitment
+                        .as_ref()
+                        .map_or(false, |commitment| selector.matches(commitment));
+                    if !matches {
+                        continue;
+                    }
+                    event.cursor = hex::encode(&cursor);
+                    if sender.send(Ok(event)).await.is_err() {
+                        return;
+                    }
+                }
+            }
+        });
+
+        Ok(Response::new(Box::pin(receiver)))
+    }
+
     /// Cancel order commitment
-    async fn cancel_order_commitment(&self, request: Request<OrderCommitment>) -> Result<Response<EmptyRequest>, Status> {
+    /// Requires signature of the order signer over `order::cancel_hash`.
+    /// The cancel is kept as a tombstone and gossiped together with the order
+    async fn cancel_order_commitment(&self, request: Request<CancelOrder>) -> Result<Response<EmptyRequest>, Status> {
         println!("cancel_order_commitment: {:?}", request);
-        let commitment = request.into_inner();
+        let mut cancel = request.into_inner();
+
+        let hash = order::parse_order_id(&cancel.order_id)
+            .map_err(|e| Status::new(tonic::Code::InvalidArgument, format!("[GRPC] {}", e)))?;
+
+        // attach the order, so peers can verify the cancel without having it
+        cancel.order_commitment = match self.db.order_commitment(&hash) {
+            Ok(Some(commitment)) => Some(commitment),
+            Ok(None) => {
+                return Err(Status::new(
+                    tonic::Code::NotFound,
+                    format!("[GRPC] Order commitment not found: {}", cancel.order_id)
+                ));
+            },
+            Err(e) => {
+                return Err(Status::new(
+                    tonic::Code::Internal,
+                    format!("[GRPC] Error retrieving order commitment: {}", e)
+                ));
+            }
+        };
 
-        match self.db.delete_order_commitment(&commitment.order_id) {
-            Ok(id) => {
+        if let Err(e) = order::verify_cancel(&cancel) {
+            println!("[GRPC] Rejecting cancel: {}", e);
+            return Err(Status::new(
+                tonic::Code::PermissionDenied,
+                format!("[GRPC] Rejecting cancel: {}", e)
+            ));
+        }
+
+        match self.db.cancel_order_commitment(&cancel) {
+            Ok(_) => {
                 if self.network_sender.send(
                     NetworkMessage::RemoveOrder {
-                        id
+                        cancel
                     }
                 ).await.is_err()
                 {
-                    println!("[GRPC] Order doesn't exist");
+                    println!("[GRPC] Error gossiping cancel");
                 };
                 Ok(Response::new(EmptyRequest::default()))
             },
             Err(e) => {
-                println!("[GRPC] Error creating order commitment: {}", e);
+                println!("[GRPC] Error cancelling order commitment: {}", e);
                 Err(Status::new(
                     tonic::Code::Internal,
-                    format!("[GRPC] Error creating order commitment: {}", e)
+                    format!("[GRPC] Error cancelling order commitment: {}", e)
                 ))
             }
         }
     }
 
     /// Create order commiment and send it to the network
-    /// First stores it in the
+    /// If gossiper is not set, the node's address is used,
+    /// so the signer is expected to sign the order with it.
+    /// Orders whose signature doesn't recover to `signer` are rejected
     async fn create_order_commitment(&self, request: Request<OrderCommitment>) -> Result<Response<OrderCommitment>, Status> {
         let mut commitment = request.into_inner();
-        match self.db.put_order_commitment(commitment.clone()) {
-            Ok(_) => {
-                commitment.gossiper = self.address.to_string();
 
+        let no_gossiper = commitment.gossiper.parse::<Address>()
+            .map(|gossiper| gossiper.is_zero())
+            .unwrap_or(commitment.gossiper.is_empty());
+        if no_gossiper {
+            commitment.gossiper = format!("{:?}", self.address);
+        }
+
+        // reject negative, malformed or out of range values
+        if let Err(e) = order::validate_fields(&commitment) {
+            println!("[GRPC] Invalid order commitment: {}", e);
+            return Err(Status::new(
+                tonic::Code::InvalidArgument,
+                format!("[GRPC] Invalid order commitment: {}", e)
+            ));
+        }
+
+        if order::is_expired(&commitment, order::unix_now()) {
+            return Err(Status::new(
+                tonic::Code::InvalidArgument,
+                format!("[GRPC] Order commitment expired at {}", commitment.expires_at)
+            ));
+        }
+
+        if let Err(e) = order::verify_signature(&commitment) {
+            println!("[GRPC] Rejecting order commitment: {}", e);
+            return Err(Status::new(
+                tonic::Code::InvalidArgument,
+                format!("[GRPC] Rejecting order commitment: {}", e)
+            ));
+        }
+
+        match self.db.put_order_commitment(commitment) {
+            Ok(commitment) => {
                 // gossip about new order commitment
                 if self.network_sender.send(
                         NetworkMessage::NewOrderCommitment {
@@ -128,8 +365,13 @@ impl<DB> NodeRpc for GRPCService<DB>
             },
             Err(e) => {
                 println!("[GRPC] Error creating order commitment: {}", e);
+                // e.g the order was cancelled before
+                let code = match *e {
+                    DBError::InvalidOrder(_) => tonic::Code::FailedPrecondition,
+                    _ => tonic::Code::Internal,
+                };
                 Err(Status::new(
-                    tonic::Code::Internal,
+                    code,
                     format!("[GRPC] Error creating order commitment: {}", e)
                 ))
             }
@@ -142,9 +384,10 @@ impl<DB> GRPCService<DB> where DB: AppStorage + Send + Sync + 'static {
         peer_id: PeerId, 
         db: Arc<DB>, 
         network_sender: Sender<NetworkMessage>,
+        events: NetworkEvents,
         address: Address
     ) -> Self {
-        GRPCService {peer_id, db, network_sender, address}
+        GRPCService {peer_id, db, network_sender, events, address}
     }
 
     /// Get key value store.
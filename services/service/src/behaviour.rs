@@ -1,20 +1,21 @@
-use std::collections::hash_map::DefaultHasher;
-use std::hash::{Hash, Hasher};
-use std::str::FromStr;
+use std::collections::{HashMap, HashSet};
 use std::sync::Arc;
 use std::task::{Poll, Context};
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use async_std::task;
-use db::rocks::RocksDB;
+use db::rocks::{RocksDB, RocksRecordStore};
 use libp2p::identity::Keypair;
 use std::io::{Cursor};
-use libp2p::{PeerId};
+use libp2p::{Multiaddr, PeerId};
 use libp2p::gossipsub::error::{PublishError, SubscriptionError};
 use libp2p::kad::{AddProviderOk, Kademlia, KademliaEvent, PeerRecord, PutRecordOk, QueryId, QueryResult, Record, Quorum,};
-use libp2p::gossipsub::{self, Gossipsub, GossipsubEvent, GossipsubMessage, IdentTopic, MessageAuthenticity, MessageId, Topic};
+use libp2p::gossipsub::{self, Gossipsub, GossipsubEvent, GossipsubMessage, IdentTopic, MessageAcceptance, MessageAuthenticity, MessageId, Topic};
 
 use libp2p::mdns::MdnsConfig;
+use libp2p::identify::{Identify, IdentifyConfig, IdentifyEvent};
+use libp2p::ping::{Ping, PingConfig, PingEvent, PingSuccess};
+use libp2p::request_response::{RequestId, RequestResponse, RequestResponseEvent, RequestResponseMessage};
 use libp2p::swarm::{NetworkBehaviour, NetworkBehaviourAction, PollParameters};
 use libp2p::{
     mdns::{Mdns, MdnsEvent},
@@ -22,32 +23,159 @@ use libp2p::{
     NetworkBehaviour,
 };
 use prost::Message;
-use common_types::{OrderCommitment, AppStorage};
+use futures::channel::oneshot;
+use rand::seq::IteratorRandom;
+use common_types::{
+    Address, OrderCommitment, OrderQuery, CancelOrder, H256, AppStorage, PeerInfo, PingResult, NetworkEvent, NetworkEvents, order
+};
+use common_types::handshake::verify_handshake;
+use common_types::digest::{self, OrderSetDigest, hashes_from_bytes, hashes_to_bytes};
+use common_types::p2p::{
+    peer_request, peer_response, PeerRequest, PeerResponse, SyncRequest, SyncResponse,
+    DigestRequest, DigestResponse, FetchRequest, FetchResponse, PushRequest, PushResponse,
+    Ping as PeerPing, Pong, Handshake,
+};
+
+use crate::protocol::{self, NodeCodec, MAX_RECONCILE_ENTRIES};
+use crate::gossip;
+use crate::scoring::{self, DuplicatePenalties};
+use crate::validation::{
+    check_cancel, check_order, validate_message, ValidMessage, MAX_GOSSIP_MESSAGE_SIZE
+};
+
+
+/// Connection data of a connected peer
+#[derive(Debug, Clone)]
+pub(crate) struct ConnectedPeer {
+    /// Remote addresses of the open connections
+    pub addresses: Vec<Multiaddr>,
+    /// Unix timestamp in seconds of the first connection
+    pub connected_since: u64,
+    pub protocols: Vec<String>,
+    /// Agent version reported by identify, e.g `nft-node/0.1.0`
+    pub agent_version: String,
+    /// Round-trip time of the last successful ping
+    pub rtt: Option<Duration>,
+}
+
+/// Ping sent to a peer on behalf of a `PingRound`
+#[derive(Debug)]
+struct PendingPing {
+    round: u64,
+    peer_id: PeerId,
+    nonce: u64,
+    sent: Instant,
+}
+
+/// Ping requested over gRPC, answered once every pinged peer responded or failed
+#[derive(Debug)]
+struct PingRound {
+    sender: oneshot::Sender<Vec<PingResult>>,
+    results: Vec<PingResult>,
+    remaining: usize,
+}
+
+/// Version of the node protocols, peers with a different one are not compatible
+pub const PROTOCOL_VERSION: &str = "/nft-market/1.0.0";
 
-use libp2p::kad::record::store::MemoryStore;
+/// Kademlia protocol, identified peers supporting it are added to the routing table
+const KADEMLIA_PROTOCOL: &str = "/ipfs/kad/1.0.0";
 
-// We create a custom network behaviour that combines Kademlia and mDNS.
+// We create a custom network behaviour that combines gossipsub, Kademlia, mDNS,
+// the node request-response protocol, identify and ping.
 #[derive(NetworkBehaviour)]
 #[behaviour(event_process = true)]
 pub(crate) struct NodeBehaviour {
     pub gsub: Gossipsub,
-    pub kademlia: Kademlia<MemoryStore>,
+    pub kademlia: Kademlia<RocksRecordStore>,
     pub mdns: Mdns,
+    pub rpc: RequestResponse<NodeCodec>,
+    pub identify: Identify,
+    pub ping: Ping,
     #[behaviour(ignore)]
-    peers: Vec<PeerId>,
+    peers: HashMap<PeerId, ConnectedPeer>,
+    #[behaviour(ignore)]
+    synced_peers: HashSet<PeerId>,
+    #[behaviour(ignore)]
+    pending_pings: HashMap<RequestId, PendingPing>,
+    #[behaviour(ignore)]
+    ping_rounds: HashMap<u64, PingRound>,
+    #[behaviour(ignore)]
+    next_ping_round: u64,
+    /// Signed handshake of the node, sent to every connected peer
+    #[behaviour(ignore)]
+    handshake: Handshake,
+    /// Ethereum addresses the connected peers proved with their handshake
+    #[behaviour(ignore)]
+    validators: HashMap<PeerId, Address>,
+    /// Peers to sync from once they are bound to a registered validator
+    #[behaviour(ignore)]
+    pending_syncs: HashSet<PeerId>,
+    /// Known orders and cancels resent by the peers
+    #[behaviour(ignore)]
+    duplicates: DuplicatePenalties,
     #[behaviour(ignore)]
     db: Arc<RocksDB>,
+    #[behaviour(ignore)]
+    events: NetworkEvents,
 }
 
 impl NetworkBehaviourEventProcess<MdnsEvent> for NodeBehaviour {
     // Called when `mdns` produces an event.
     fn inject_event(&mut self, event: MdnsEvent) {
-        if let MdnsEvent::Discovered(list) = event {
-            for (peer_id, multiaddr) in list {
-                println!("[MDNS] Discovered peer {:?} {:?}", peer_id, multiaddr);
-                self.gsub.add_explicit_peer(&peer_id);
-                self.kademlia.add_address(&peer_id, multiaddr);
-                self.peers.push(peer_id);
+        match event {
+            MdnsEvent::Discovered(list) => {
+                for (peer_id, multiaddr) in list {
+                    println!("[MDNS] Discovered peer {:?} {:?}", peer_id, multiaddr);
+                    self.gsub.add_explicit_peer(&peer_id);
+                    self.kademlia.add_address(&peer_id, multiaddr);
+                }
+            },
+            MdnsEvent::Expired(list) => {
+                for (peer_id, multiaddr) in list {
+                    println!("[MDNS] Expired peer {:?} {:?}", peer_id, multiaddr);
+                    self.kademlia.remove_address(&peer_id, &multiaddr);
+                }
+            }
+        }
+    }
+}
+
+impl NetworkBehaviourEventProcess<IdentifyEvent> for NodeBehaviour {
+    fn inject_event(&mut self, event: IdentifyEvent) {
+        match event {
+            IdentifyEvent::Received { peer_id, info } => {
+                println!("[IDENTIFY] Identified {:?} running {}", peer_id, info.agent_version);
+                if info.protocols.iter().any(|protocol| protocol == KADEMLIA_PROTOCOL) {
+                    for address in &info.listen_addrs {
+                        self.kademlia.add_address(&peer_id, address.clone());
+                    }
+                }
+                if let Some(peer) = self.peers.get_mut(&peer_id) {
+                    peer.protocols = info.protocols;
+                    peer.agent_version = info.agent_version;
+                }
+            },
+            IdentifyEvent::Error { peer_id, error } => {
+                println!("[IDENTIFY] Couldn't identify {:?}: {:?}", peer_id, error);
+            },
+            IdentifyEvent::Sent { .. } | IdentifyEvent::Pushed { .. } => {}
+        }
+    }
+}
+
+impl NetworkBehaviourEventProcess<PingEvent> for NodeBehaviour {
+    // connections failing pings are closed by the ping behaviour itself
+    fn inject_event(&mut self, event: PingEvent) {
+        match event.result {
+            Ok(PingSuccess::Ping { rtt }) => {
+                if let Some(peer) = self.peers.get_mut(&event.peer) {
+                    peer.rtt = Some(rtt);
+                }
+            },
+            Ok(PingSuccess::Pong) => {},
+            Err(e) => {
+                println!("[PING] Ping to {:?} failed: {:?}", event.peer, e);
             }
         }
     }
@@ -63,33 +191,62 @@ impl NetworkBehaviourEventProcess<GossipsubEvent> for NodeBehaviour {
             } => {
                 let topic = message.topic.to_string();
                 println!("{}", topic);
-                if topic.to_string() == "order_commitment" {
-                    let order_commitment = OrderCommitment::decode(
-                        Cursor::new(message.data.to_vec())
-                    );
-                    match order_commitment {
-                        Ok(order_commitment) => {
-                            if self.db.put_order_commitment(order_commitment).is_err() {
-                                println!("[GoSSIPSUB] Couldn't store order commitment in db");
-                            };
-                        },
-                        Err(e) => {
-                            println!("{:?}", e);
-                        }
+
+                // only registered validators may relay orders, others are dropped without penalty
+                if !self.is_registered(&propagation_source) {
+                    println!("[GOSSIPSUB] Dropping message from unregistered peer {:?}", propagation_source);
+                    if let Err(e) = self.gsub.report_message_validation_result(
+                        &message_id,
+                        &propagation_source,
+                        MessageAcceptance::Ignore
+                    ) {
+                        println!("[GOSSIPSUB] Couldn't report validation result {:?}", e);
                     }
+                    return;
                 }
-                else if topic.to_string() == "cancel_order" {
-                    let order_id = String::from_utf8(message.data.to_vec());
-                    match order_id {
-                        Ok(id) => {
-                            if self.db.delete_order_commitment(&id).is_err() {
-                                println!("[GoSSIPSUB] Couldn't delete order commitment in db");
-                            };
-                        },
-                        Err(e) => {
-                            println!("{:?}", e);
+
+                // every message goes through validation before it's accepted and forwarded,
+                // a publisher connected to the node has to send with the address of its handshake
+                let bound = message.source.as_ref().and_then(|source| self.validators.get(source)).cloned();
+                let (acceptance, validated) = match validate_message(self.db.as_ref(), &message, bound.as_ref()) {
+                    Ok(valid) => (MessageAcceptance::Accept, Some(valid)),
+                    Err(invalid) => {
+                        println!("[GOSSIPSUB] Invalid message from {:?}: {}", propagation_source, invalid.reason);
+                        if invalid.duplicate {
+                            let score = self.duplicates.record(propagation_source);
+                            self.gsub.set_application_score(&propagation_source, score);
                         }
+                        (invalid.acceptance, None)
                     }
+                };
+                if let Err(e) = self.gsub.report_message_validation_result(
+                    &message_id,
+                    &propagation_source,
+                    acceptance
+                ) {
+                    println!("[GOSSIPSUB] Couldn't report validation result {:?}", e);
+                }
+
+                match validated {
+                    Some(ValidMessage::Order(order_commitment)) => {
+                        match self.db.put_order_commitment(order_commitment) {
+                            Ok(order_commitment) => self.events.publish(NetworkEvent::OrderReceived {
+                                source: propagation_source,
+                                order_commitment,
+                            }),
+                            Err(_) => println!("[GoSSIPSUB] Couldn't store order commitment in db"),
+                        };
+                    },
+                    Some(ValidMessage::Cancel(cancel)) => {
+                        match self.db.cancel_order_commitment(&cancel) {
+                            Ok(_) => self.events.publish(NetworkEvent::OrderCancelled {
+                                source: propagation_source,
+                                cancel,
+                            }),
+                            Err(_) => println!("[GoSSIPSUB] Couldn't delete order commitment in db"),
+                        };
+                    },
+                    None => {}
                 }
             },
             GossipsubEvent::Subscribed{
@@ -108,6 +265,66 @@ impl NetworkBehaviourEventProcess<GossipsubEvent> for NodeBehaviour {
     }
 }
 
+impl NetworkBehaviourEventProcess<RequestResponseEvent<PeerRequest, PeerResponse>> for NodeBehaviour {
+    fn inject_event(&mut self, event: RequestResponseEvent<PeerRequest, PeerResponse>) {
+        match event {
+            RequestResponseEvent::Message { peer, message } => match message {
+                RequestResponseMessage::Request { request, channel, .. } => {
+                    let response = match request.request {
+                        Some(peer_request::Request::Sync(sync)) => self.sync_page(sync),
+                        Some(peer_request::Request::Digest(digest)) => self.compare_digest(digest),
+                        Some(peer_request::Request::Fetch(fetch)) => self.fetch(fetch),
+                        Some(peer_request::Request::Ping(ping)) => PeerResponse {
+                            response: Some(peer_response::Response::Pong(Pong { nonce: ping.nonce })),
+                        },
+                        Some(peer_request::Request::Push(push)) => {
+                            self.merge(peer, push.order_commitments, push.cancels);
+                            PeerResponse { response: Some(peer_response::Response::Push(PushResponse {})) }
+                        },
+                        Some(peer_request::Request::Handshake(handshake)) => {
+                            self.bind_validator(peer, &handshake);
+                            PeerResponse {
+                                response: Some(peer_response::Response::Handshake(self.handshake.clone())),
+                            }
+                        },
+                        None => {
+                            println!("[RPC] Empty request from {:?}", peer);
+                            return;
+                        }
+                    };
+                    if self.rpc.send_response(channel, response).is_err() {
+                        println!("[RPC] Couldn't respond to {:?}", peer);
+                    }
+                },
+                RequestResponseMessage::Response { request_id, response } => match response.response {
+                    Some(peer_response::Response::Sync(sync)) => self.merge_sync_page(peer, sync),
+                    Some(peer_response::Response::Digest(digest)) => self.reconcile(peer, digest),
+                    Some(peer_response::Response::Fetch(fetch)) => {
+                        self.merge(peer, fetch.order_commitments, fetch.cancels);
+                    },
+                    Some(peer_response::Response::Push(_)) => {},
+                    Some(peer_response::Response::Pong(pong)) => self.finish_ping(&request_id, Ok(pong.nonce)),
+                    Some(peer_response::Response::Handshake(handshake)) => self.bind_validator(peer, &handshake),
+                    None => println!("[RPC] Empty response from {:?}", peer),
+                },
+            },
+            RequestResponseEvent::OutboundFailure { peer, request_id, error } => {
+                println!("[RPC] Request to {:?} failed: {:?}", peer, error);
+                if self.pending_pings.contains_key(&request_id) {
+                    self.finish_ping(&request_id, Err(format!("{:?}", error)));
+                } else {
+                    // allow retrying the sync on the next connection
+                    self.synced_peers.remove(&peer);
+                }
+            },
+            RequestResponseEvent::InboundFailure { peer, error, .. } => {
+                println!("[RPC] Request from {:?} failed: {:?}", peer, error);
+            },
+            RequestResponseEvent::ResponseSent { .. } => {}
+        }
+    }
+}
+
 impl NetworkBehaviourEventProcess<KademliaEvent> for NodeBehaviour {
     fn inject_event(&mut self, event: KademliaEvent) {
         match event {
@@ -120,57 +337,78 @@ impl NodeBehaviour {
     pub fn new(
         local_key: &Keypair, 
         peer_id: PeerId, 
-        bootnode: Option<String>,
+        bootnodes: &[(PeerId, Multiaddr)],
         db: Arc<RocksDB>,
+        handshake: Handshake,
+        events: NetworkEvents,
+        agent_version: String,
     ) -> Self {
-        // create message id function for gossipsub
-        let message_id_fn = |message: &GossipsubMessage| {
-            let mut hasher = DefaultHasher::new();
-            message.data.hash(&mut hasher);
-            MessageId::from(hasher.finish().to_string())
-        };
-
         // Gossipsub configuration
+        // messages are forwarded only after `validate_message` accepts them
         let gossipsub_config = gossipsub::GossipsubConfigBuilder::default()
             .heartbeat_interval(Duration::from_secs(1))
-            .validation_mode(gossipsub::ValidationMode::None)
-            .message_id_fn(message_id_fn)
+            .validation_mode(gossipsub::ValidationMode::Strict)
+            .validate_messages()
+            .max_transmit_size(MAX_GOSSIP_MESSAGE_SIZE)
+            .message_id_fn(gossip::message_id)
             .build() 
             .expect("Valid configuration");
         
-        let gossipsub: Gossipsub = 
+        let mut gossipsub: Gossipsub = 
             Gossipsub::new(MessageAuthenticity::Signed(local_key.clone()), gossipsub_config)
                 .expect("incorrect config");
+
+        // peers sending invalid messages lose score and get graylisted
+        let (score_params, score_thresholds) = scoring::peer_score_params();
+        gossipsub.with_peer_score(score_params, score_thresholds)
+            .expect("incorrect peer score params");
         
         // add static id 
         // gossipsub.add_explicit_peer(explicit_id.unwrap_or());
 
         // Create a Kademlia behaviour.
-        let store = MemoryStore::new(peer_id);
+        // records are persisted, e.g the slot number survives restarts
+        let store = RocksRecordStore::new(db.clone(), peer_id);
         let kademlia = Kademlia::new(peer_id, store);
         let mdns = task::block_on(Mdns::new(MdnsConfig::default())).unwrap();
+
+        // peers exchange listen addresses and versions
+        let identify = Identify::new(
+            IdentifyConfig::new(PROTOCOL_VERSION.to_string(), local_key.public())
+                .with_agent_version(agent_version)
+        );
+        // dead connections are closed after failed pings
+        let ping = Ping::new(PingConfig::new().with_keep_alive(true));
         
         let mut behaviour = NodeBehaviour {
             kademlia, mdns, 
             gsub: gossipsub,
-            peers: Vec::new(),
-            db
+            rpc: protocol::node_protocol(),
+            identify,
+            ping,
+            peers: HashMap::new(),
+            synced_peers: HashSet::new(),
+            pending_pings: HashMap::new(),
+            ping_rounds: HashMap::new(),
+            next_ping_round: 0,
+            handshake,
+            validators: HashMap::new(),
+            pending_syncs: HashSet::new(),
+            duplicates: DuplicatePenalties::default(),
+            db,
+            events,
         };
 
-        // create topic for subscription
-        let order_topic = IdentTopic::new("order_commitment");
-        let cancel_order = IdentTopic::new("cancel_order");
-        let ping_topic = IdentTopic::new("ping");
-
-        // subscribe to node topic
-        behaviour.gsub.subscribe(&order_topic).unwrap();
-        behaviour.gsub.subscribe(&cancel_order).unwrap();
-        behaviour.gsub.subscribe(&ping_topic).unwrap();
+        // subscribe to the versioned node topics
+        for topic in gossip::topics() {
+            behaviour.gsub.subscribe(&topic).unwrap();
+        }
         
-        if let Some(bootnode) = bootnode {
-            let peer_id = PeerId::from_str(&bootnode).expect("invalid peer id");
-            behaviour.gsub.add_explicit_peer(&peer_id);
-        };
+        // bootnodes are the entry points of the kademlia routing table
+        for (peer_id, address) in bootnodes {
+            behaviour.kademlia.add_address(peer_id, address.clone());
+            behaviour.gsub.add_explicit_peer(peer_id);
+        }
 
         behaviour
     }
@@ -185,13 +423,476 @@ impl NodeBehaviour {
         self.gsub.publish(topic, message)
     }
 
+    /// Check if messages published on the topic reach the peer directly.
+    /// Publishing floods all peers subscribed to the topic
+    pub fn is_topic_peer(&self, peer_id: &PeerId, topic: &IdentTopic) -> bool {
+        let topic = topic.hash();
+        self.gsub
+            .all_peers()
+            .any(|(peer, topics)| peer == peer_id && topics.contains(&&topic))
+    }
+
     /// Subscribe to new topic
     pub fn subscribe(&mut self, topic: IdentTopic) -> Result<bool, SubscriptionError> {
         self.gsub.subscribe(&topic)
     }
 
-    /// Get list of peers
-    pub fn peers(&self) -> Vec<PeerId> {
-        self.peers.clone()
+    /// Track new connection of the peer
+    pub fn peer_connected(&mut self, peer_id: PeerId, address: Multiaddr) {
+        let peer = self.peers.entry(peer_id).or_insert_with(|| ConnectedPeer {
+            addresses: Vec::new(),
+            connected_since: order::unix_now(),
+            protocols: Vec::new(),
+            agent_version: String::new(),
+            rtt: None,
+        });
+        if !peer.addresses.contains(&address) {
+            peer.addresses.push(address);
+        }
+    }
+
+    /// Track closed connection of the peer, forgetting the peer once the last one is closed
+    pub fn peer_disconnected(&mut self, peer_id: &PeerId, address: &Multiaddr, num_established: u32) {
+        if num_established == 0 {
+            self.peers.remove(peer_id);
+            self.validators.remove(peer_id);
+            self.pending_syncs.remove(peer_id);
+        } else if let Some(peer) = self.peers.get_mut(peer_id) {
+            peer.addresses.retain(|known| known != address);
+        }
+    }
+
+    /// Get connected peers with their gossipsub score
+    pub fn peers(&self) -> Vec<PeerInfo> {
+        self.peers
+            .iter()
+            .map(|(peer_id, peer)| PeerInfo {
+                address: self.validators.get(peer_id).map_or(String::new(), |address| format!("{:?}", address)),
+                registered: self.is_registered(peer_id),
+                peer_id: peer_id.to_string(),
+                addresses: peer.addresses.iter().map(|address| address.to_string()).collect(),
+                connected_since: peer.connected_since,
+                protocols: peer.protocols.clone(),
+                agent_version: peer.agent_version.clone(),
+                rtt_millis: peer.rtt.map_or(0, |rtt| rtt.as_millis() as u64),
+                score: self.gsub.peer_score(peer_id).unwrap_or_default(),
+            })
+            .collect()
+    }
+
+    /// Signed handshake of the node, its address is the sender of the published gossip
+    pub fn handshake(&self) -> &Handshake {
+        &self.handshake
+    }
+
+    /// Send the signed handshake to the peer, answered with the handshake of the peer
+    pub fn start_handshake(&mut self, peer_id: PeerId) {
+        let request = PeerRequest {
+            request: Some(peer_request::Request::Handshake(self.handshake.clone())),
+        };
+        self.rpc.send_request(&peer_id, request);
+    }
+
+    /// Bind the peer to the Ethereum address proved by its handshake
+    fn bind_validator(&mut self, peer_id: PeerId, handshake: &Handshake) {
+        match verify_handshake(&peer_id, handshake) {
+            Ok(address) => {
+                println!("[RPC] Peer {:?} is bound to {:?}", peer_id, address);
+                self.validators.insert(peer_id, address);
+                self.retry_syncs();
+            },
+            Err(e) => println!("[RPC] Invalid handshake from {:?}: {}", peer_id, e),
+        }
+    }
+
+    /// Check if the peer proved an address registered as a validator in the auction protocol
+    pub fn is_registered(&self, peer_id: &PeerId) -> bool {
+        self.validators
+            .get(peer_id)
+            .map_or(false, |address| self.db.is_registered_validator(address))
+    }
+
+    /// Get gossipsub score of every known peer
+    pub fn peer_scores(&self) -> Vec<(PeerId, f64)> {
+        self.gsub
+            .all_peers()
+            .filter_map(|(peer_id, _)| {
+                self.gsub.peer_score(peer_id).map(|score| (*peer_id, score))
+            })
+            .collect()
+    }
+
+    /// Let the duplicate penalties of the peers wear off
+    pub fn decay_duplicate_penalties(&mut self) {
+        for (peer_id, score) in self.duplicates.decay() {
+            self.gsub.set_application_score(&peer_id, score);
+        }
+    }
+
+    /// Ping the peer, or all connected peers if `target` is `None`,
+    /// results are sent once every peer responded or failed
+    pub fn ping(&mut self, target: Option<PeerId>, sender: oneshot::Sender<Vec<PingResult>>) {
+        let peers: Vec<PeerId> = match target {
+            Some(peer_id) => vec![peer_id],
+            None => self.peers.keys().cloned().collect(),
+        };
+        if peers.is_empty() {
+            if sender.send(Vec::new()).is_err() {
+                println!("[RPC] Couldn't send ping results");
+            }
+            return;
+        }
+
+        let round = self.next_ping_round;
+        self.next_ping_round += 1;
+        self.ping_rounds.insert(round, PingRound { sender, results: Vec::new(), remaining: peers.len() });
+
+        for peer_id in peers {
+            let nonce = rand::random();
+            let request = PeerRequest { request: Some(peer_request::Request::Ping(PeerPing { nonce })) };
+            let request_id = self.rpc.send_request(&peer_id, request);
+            self.pending_pings.insert(request_id, PendingPing { round, peer_id, nonce, sent: Instant::now() });
+        }
+    }
+
+    /// Record the pong or failure of the ping, answering the round once it's complete
+    fn finish_ping(&mut self, request_id: &RequestId, outcome: Result<u64, String>) {
+        let pending = match self.pending_pings.remove(request_id) {
+            Some(pending) => pending,
+            None => return,
+        };
+        let mut result = PingResult {
+            peer_id: pending.peer_id.to_string(),
+            ..Default::default()
+        };
+        match outcome {
+            Ok(nonce) if nonce == pending.nonce => {
+                result.success = true;
+                result.latency_millis = pending.sent.elapsed().as_millis() as u64;
+            },
+            Ok(_) => result.error = "unexpected pong".to_string(),
+            Err(error) => result.error = error,
+        }
+
+        let complete = match self.ping_rounds.get_mut(&pending.round) {
+            Some(round) => {
+                round.results.push(result);
+                round.remaining -= 1;
+                round.remaining == 0
+            },
+            None => false,
+        };
+        if complete {
+            if let Some(round) = self.ping_rounds.remove(&pending.round) {
+                if round.sender.send(round.results).is_err() {
+                    println!("[RPC] Couldn't send ping results");
+                }
+            }
+        }
+    }
+
+    /// Request open orders of the peer, once per peer.
+    /// Deferred until the peer is bound to a validator that is known to be registered,
+    /// since orders of other peers are dropped
+    pub fn start_sync(&mut self, peer_id: PeerId) {
+        if !self.is_registered(&peer_id) {
+            self.pending_syncs.insert(peer_id);
+            return;
+        }
+        if !self.synced_peers.insert(peer_id) {
+            return;
+        }
+        println!("[RPC] Syncing orders from {:?}", peer_id);
+        self.request_sync_page(peer_id, String::new());
+    }
+
+    /// Start deferred syncs with peers that became registered validators,
+    /// e.g once web3 loaded the validator set
+    pub fn retry_syncs(&mut self) {
+        let registered: Vec<PeerId> = self.pending_syncs
+            .iter()
+            .filter(|peer_id| self.is_registered(peer_id))
+            .cloned()
+            .collect();
+        for peer_id in registered {
+            self.pending_syncs.remove(&peer_id);
+            self.start_sync(peer_id);
+        }
+    }
+
+    /// Request the page of open orders that follows the cursor
+    fn request_sync_page(&mut self, peer_id: PeerId, cursor: String) {
+        let request = PeerRequest {
+            request: Some(peer_request::Request::Sync(SyncRequest {
+                cursor,
+                page_size: protocol::SYNC_PAGE_SIZE,
+            })),
+        };
+        self.rpc.send_request(&peer_id, request);
+    }
+
+    /// Serve a page of open orders to the peer
+    fn sync_page(&self, request: SyncRequest) -> PeerResponse {
+        let query = OrderQuery {
+            filter: None,
+            page_size: request.page_size,
+            cursor: request.cursor,
+        };
+        let sync = match self.db.query_order_commitments(&query) {
+            Ok(page) => SyncResponse {
+                order_commitments: page.order_commitments,
+                next_cursor: page.next_cursor,
+            },
+            Err(e) => {
+                println!("[RPC] Couldn't read orders for sync: {}", e);
+                SyncResponse::default()
+            }
+        };
+        PeerResponse { response: Some(peer_response::Response::Sync(sync)) }
+    }
+
+    /// Store orders received from the peer and request the next page
+    fn merge_sync_page(&mut self, peer_id: PeerId, sync: SyncResponse) {
+        // the validator set may have changed since the request, retry once it's known again
+        if !self.is_registered(&peer_id) {
+            self.synced_peers.remove(&peer_id);
+            self.pending_syncs.insert(peer_id);
+            return;
+        }
+        self.merge(peer_id, sync.order_commitments, Vec::new());
+
+        if !sync.next_cursor.is_empty() {
+            self.request_sync_page(peer_id, sync.next_cursor);
+        }
     }
-}
\ No newline at end of file
+
+    /// Store orders and cancels received from the peer outside of gossip.
+    /// They go through the same checks as gossiped ones
+    fn merge(&mut self, peer_id: PeerId, order_commitments: Vec<OrderCommitment>, cancels: Vec<CancelOrder>) {
+        if !self.is_registered(&peer_id) {
+            println!("[RPC] Skipping orders from unregistered peer {:?}", peer_id);
+            return;
+        }
+        let mut merged = 0;
+        // cancels first, so cancelled orders among the received ones are skipped
+        for cancel in cancels {
+            let hash = match check_cancel(&cancel) {
+                Ok(hash) => hash,
+                Err(invalid) => {
+                    println!("[RPC] Skipping cancel from {:?}: {}", peer_id, invalid.reason);
+                    continue;
+                }
+            };
+            if self.db.is_cancelled(&hash).unwrap_or(false) {
+                continue;
+            }
+            match self.db.cancel_order_commitment(&cancel) {
+                Ok(_) => {
+                    merged += 1;
+                    self.events.publish(NetworkEvent::OrderCancelled { source: peer_id, cancel });
+                },
+                Err(e) => println!("[RPC] Couldn't store cancel: {}", e),
+            }
+        }
+        for order_commitment in order_commitments {
+            let hash = match check_order(self.db.as_ref(), &order_commitment) {
+                Ok(hash) => hash,
+                Err(invalid) => {
+                    println!("[RPC] Skipping order from {:?}: {}", peer_id, invalid.reason);
+                    continue;
+                }
+            };
+            if self.db.order_commitment(&hash).map_or(false, |stored| stored.is_some()) {
+                continue;
+            }
+            match self.db.put_order_commitment(order_commitment) {
+                Ok(order_commitment) => {
+                    merged += 1;
+                    self.events.publish(NetworkEvent::OrderReceived { source: peer_id, order_commitment });
+                },
+                Err(e) => println!("[RPC] Couldn't store order: {}", e),
+            }
+        }
+        println!("[RPC] Merged {} orders and cancels from {:?}", merged, peer_id);
+    }
+
+    /// Send the order directly to the peer, e.g the current slot processor,
+    /// so it doesn't depend on gossip propagation
+    pub fn forward_order(&mut self, peer_id: &PeerId, order_commitment: OrderCommitment) {
+        let request = PeerRequest {
+            request: Some(peer_request::Request::Push(PushRequest {
+                order_commitments: vec![order_commitment],
+                cancels: Vec::new(),
+            })),
+        };
+        self.rpc.send_request(peer_id, request);
+    }
+
+    /// Start anti-entropy round with a random connected validator
+    pub fn start_anti_entropy(&mut self) {
+        let peer_id = match self.peers
+            .keys()
+            .filter(|peer_id| self.is_registered(peer_id))
+            .cloned()
+            .choose(&mut rand::thread_rng())
+        {
+            Some(peer_id) => peer_id,
+            None => return,
+        };
+        self.request_digest(&peer_id, 0);
+    }
+
+    /// Send the digest of the local order set to the peer,
+    /// asking for the page of differing buckets starting with `start_bucket`
+    fn request_digest(&mut self, peer_id: &PeerId, start_bucket: u32) {
+        let digest = match self.db.order_set_digest() {
+            Ok(digest) => digest,
+            Err(e) => {
+                println!("[RPC] Couldn't compute order set digest: {}", e);
+                return;
+            }
+        };
+        let request = PeerRequest {
+            request: Some(peer_request::Request::Digest(DigestRequest {
+                root: digest.root.as_bytes().to_vec(),
+                buckets: digest.buckets.iter().map(|leaf| leaf.as_bytes().to_vec()).collect(),
+                start_bucket,
+            })),
+        };
+        self.rpc.send_request(peer_id, request);
+    }
+
+    /// Answer the digest of the peer with a page of contents of the differing buckets
+    fn compare_digest(&self, request: DigestRequest) -> PeerResponse {
+        let mut response = DigestResponse::default();
+        let remote = OrderSetDigest::from_leaves(hashes_from_bytes(&request.buckets));
+
+        match self.db.order_set() {
+            Ok(buckets) => {
+                let differing = OrderSetDigest::from_buckets(&buckets).differing_buckets(&remote);
+                let (page, next_bucket) = digest::digest_page(
+                    &buckets,
+                    &differing,
+                    request.start_bucket as usize,
+                    digest::MAX_DIGEST_PAGE_HASHES,
+                );
+                response.buckets = page;
+                if let Some(next_bucket) = next_bucket {
+                    response.more = true;
+                    response.next_bucket = next_bucket as u32;
+                }
+            },
+            Err(e) => println!("[RPC] Couldn't compute order set digest: {}", e),
+        }
+
+        PeerResponse { response: Some(peer_response::Response::Digest(response)) }
+    }
+
+    /// Fetch what's missing locally and push what's missing on the peer
+    fn reconcile(&mut self, peer_id: PeerId, response: DigestResponse) {
+        if response.more {
+            self.request_digest(&peer_id, response.next_bucket);
+        }
+        if response.buckets.is_empty() {
+            return;
+        }
+        let local = match self.db.order_set() {
+            Ok(local) => local,
+            Err(e) => {
+                println!("[RPC] Couldn't read order set: {}", e);
+                return;
+            }
+        };
+
+        let (mut missing, mut extra) = digest::reconcile_page(&local, &response.buckets);
+        // the rest is reconciled in the following rounds
+        missing.orders.truncate(MAX_RECONCILE_ENTRIES);
+        missing.tombstones.truncate(MAX_RECONCILE_ENTRIES);
+        extra.orders.truncate(MAX_RECONCILE_ENTRIES);
+        extra.tombstones.truncate(MAX_RECONCILE_ENTRIES);
+
+        println!(
+            "[RPC] Reconciling with {:?}: {} missing, {} extra",
+            peer_id,
+            missing.orders.len() + missing.tombstones.len(),
+            extra.orders.len() + extra.tombstones.len(),
+        );
+
+        if !missing.is_empty() {
+            let request = PeerRequest {
+                request: Some(peer_request::Request::Fetch(FetchRequest {
+                    order_hashes: hashes_to_bytes(&missing.orders),
+                    tombstone_hashes: hashes_to_bytes(&missing.tombstones),
+                })),
+            };
+            self.rpc.send_request(&peer_id, request);
+        }
+        if !extra.is_empty() {
+            let (order_commitments, cancels) = self.load_entries(&extra.orders, &extra.tombstones);
+            let request = PeerRequest {
+                request: Some(peer_request::Request::Push(PushRequest { order_commitments, cancels })),
+            };
+            self.rpc.send_request(&peer_id, request);
+        }
+    }
+
+    /// Serve orders and cancels requested by the peer
+    fn fetch(&self, request: FetchRequest) -> PeerResponse {
+        let mut orders = hashes_from_bytes(&request.order_hashes);
+        let mut tombstones = hashes_from_bytes(&request.tombstone_hashes);
+        orders.truncate(MAX_RECONCILE_ENTRIES);
+        tombstones.truncate(MAX_RECONCILE_ENTRIES);
+
+        let (order_commitments, cancels) = self.load_entries(&orders, &tombstones);
+        PeerResponse {
+            response: Some(peer_response::Response::Fetch(FetchResponse { order_commitments, cancels })),
+        }
+    }
+
+    /// Load stored orders and cancels, unknown ones are skipped
+    fn load_entries(&self, orders: &[H256], tombstones: &[H256]) -> (Vec<OrderCommitment>, Vec<CancelOrder>) {
+        let order_commitments = orders
+            .iter()
+            .filter_map(|hash| self.db.order_commitment(hash).ok().flatten())
+            .collect();
+        let cancels = tombstones
+            .iter()
+            .filter_map(|hash| self.db.tombstone(hash).ok().flatten())
+            // unsigned tombstones of matched orders would be rejected by the peer
+            .filter(|cancel| cancel.signature.is_some())
+            .collect();
+        (order_commitments, cancels)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use common_types::AppStorage;
+    use common_types::digest::{self, OrderSetDigest};
+
+    use crate::validation::tests::{open_db, signed_order};
+
+    #[test]
+    fn matched_order_is_not_reconciled() {
+        let matched = open_db();
+        let open = open_db();
+        let empty = open_db();
+        let order_commitment = signed_order();
+        matched.put_order_commitment(order_commitment.clone()).unwrap();
+        matched.remove_matched_orders(std::slice::from_ref(&order_commitment)).unwrap();
+        open.put_order_commitment(order_commitment).unwrap();
+
+        // the unsigned tombstone isn't part of the digest, so peers that dropped the order agree
+        assert_eq!(matched.order_set_digest().unwrap(), empty.order_set_digest().unwrap());
+
+        // the matched order isn't fetched from a peer that still has it open
+        let local = matched.order_set().unwrap();
+        let remote = open.order_set().unwrap();
+        let differing = OrderSetDigest::from_buckets(&remote)
+            .differing_buckets(&matched.order_set_digest().unwrap());
+        let (page, _) = digest::digest_page(&remote, &differing, 0, digest::MAX_DIGEST_PAGE_HASHES);
+        let (missing, extra) = digest::reconcile_page(&local, &page);
+        assert!(missing.is_empty());
+        assert!(extra.is_empty());
+    }
+}
@@ -0,0 +1,98 @@
+use std::time::{Duration, Instant};
+
+use libp2p::multiaddr::Protocol;
+use libp2p::{Multiaddr, PeerId};
+
+/// Delay before the first redial of a bootnode
+pub const INITIAL_DIAL_BACKOFF: Duration = Duration::from_secs(1);
+
+/// Upper bound of the redial delay
+pub const MAX_DIAL_BACKOFF: Duration = Duration::from_secs(5 * 60);
+
+/// How often bootnodes due for a redial are checked
+pub const DIAL_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Peer id of the `/p2p/<id>` component of the address
+pub fn peer_id_of(address: &Multiaddr) -> Option<PeerId> {
+    address.iter().find_map(|protocol| match protocol {
+        Protocol::P2p(hash) => PeerId::from_multihash(hash).ok(),
+        _ => None,
+    })
+}
+
+#[derive(Debug)]
+struct Bootnode {
+    peer_id: PeerId,
+    address: Multiaddr,
+    backoff: Duration,
+    /// `None` while connected or being dialed
+    next_dial: Option<Instant>,
+}
+
+/// Bootnodes of the node, redialed with exponential backoff
+/// whenever dialing fails or the connection is closed
+#[derive(Debug, Default)]
+pub(crate) struct Bootnodes {
+    nodes: Vec<Bootnode>,
+}
+
+impl Bootnodes {
+    /// Bootnodes without peer id can't be tracked, so they are skipped
+    pub fn new(addresses: Vec<Multiaddr>) -> Self {
+        let now = Instant::now();
+        let nodes = addresses
+            .into_iter()
+            .filter_map(|address| match peer_id_of(&address) {
+                Some(peer_id) => Some(Bootnode {
+                    peer_id,
+                    address,
+                    backoff: INITIAL_DIAL_BACKOFF,
+                    next_dial: Some(now),
+                }),
+                None => {
+                    println!("[BOOTNODE] Skipping {}: missing /p2p/<peer id>", address);
+                    None
+                }
+            })
+            .collect();
+        Bootnodes { nodes }
+    }
+
+    /// Peer ids and addresses of all bootnodes
+    pub fn addresses(&self) -> Vec<(PeerId, Multiaddr)> {
+        self.nodes
+            .iter()
+            .map(|node| (node.peer_id, node.address.clone()))
+            .collect()
+    }
+
+    /// Addresses that are due for dialing at `now`
+    pub fn due(&mut self, now: Instant) -> Vec<Multiaddr> {
+        self.nodes
+            .iter_mut()
+            .filter(|node| node.next_dial.map_or(false, |next_dial| next_dial <= now))
+            .map(|node| {
+                node.next_dial = None;
+                node.address.clone()
+            })
+            .collect()
+    }
+
+    /// Schedule a redial of the bootnode after failed dial or closed connection,
+    /// doubling its backoff
+    pub fn schedule_redial(&mut self, peer_id: &PeerId, now: Instant) {
+        if let Some(node) = self.nodes.iter_mut().find(|node| &node.peer_id == peer_id) {
+            println!("[BOOTNODE] Redialing {:?} in {:?}", peer_id, node.backoff);
+            node.next_dial = Some(now + node.backoff);
+            node.backoff = (node.backoff * 2).min(MAX_DIAL_BACKOFF);
+        }
+    }
+
+    /// Reset the backoff of the connected bootnode
+    pub fn connected(&mut self, peer_id: &PeerId) {
+        if let Some(node) = self.nodes.iter_mut().find(|node| &node.peer_id == peer_id) {
+            node.backoff = INITIAL_DIAL_BACKOFF;
+            node.next_dial = None;
+        }
+    }
+}
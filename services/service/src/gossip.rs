@@ -0,0 +1,158 @@
+use std::io::Cursor;
+
+use libp2p::gossipsub::{GossipsubMessage, IdentTopic, MessageId};
+use prost::Message;
+use sha2::{Digest, Sha256};
+use common_types::{CancelOrder, H256, OrderCommitment, Signature, hex, order};
+use common_types::p2p::{GossipEnvelope, Handshake};
+use common_types::p2p::gossip_envelope::{MessageType, Payload};
+
+/// Version of the gossip protocol.
+/// Topics are versioned, so during an upgrade nodes can subscribe to both versions
+/// while messages of other versions are rejected
+pub const GOSSIP_VERSION: u32 = 1;
+
+/// Topic of new order commitments
+pub const ORDERS_TOPIC: &str = "/nft-market/1/orders";
+
+/// Topic of signed order cancels
+pub const CANCELS_TOPIC: &str = "/nft-market/1/cancels";
+
+/// Gossip topics of the node
+pub fn topics() -> Vec<IdentTopic> {
+    vec![IdentTopic::new(ORDERS_TOPIC), IdentTopic::new(CANCELS_TOPIC)]
+}
+
+/// Topic the message type is published on
+pub fn topic(message_type: MessageType) -> Option<IdentTopic> {
+    match message_type {
+        MessageType::Order => Some(IdentTopic::new(ORDERS_TOPIC)),
+        MessageType::Cancel => Some(IdentTopic::new(CANCELS_TOPIC)),
+        MessageType::Unknown => None,
+    }
+}
+
+/// Wrap the payload in the envelope of the current version,
+/// sent by the address of the node handshake
+fn envelope(handshake: &Handshake, message_type: MessageType, payload: Payload) -> Vec<u8> {
+    let envelope = GossipEnvelope {
+        version: GOSSIP_VERSION,
+        message_type: message_type as i32,
+        sender: handshake.address.clone(),
+        timestamp: order::unix_now(),
+        payload: Some(payload),
+        sender_signature: handshake.signature.clone(),
+    };
+    let mut buff = Vec::new();
+    buff.reserve(envelope.encoded_len());
+    envelope.encode(&mut buff).unwrap();
+    buff
+}
+
+/// Encoded envelope of the order commitment
+pub fn order_envelope(handshake: &Handshake, order_commitment: OrderCommitment) -> Vec<u8> {
+    envelope(handshake, MessageType::Order, Payload::OrderCommitment(order_commitment))
+}
+
+/// Encoded envelope of the cancel
+pub fn cancel_envelope(handshake: &Handshake, cancel: CancelOrder) -> Vec<u8> {
+    envelope(handshake, MessageType::Cancel, Payload::Cancel(cancel))
+}
+
+/// Signature bytes of the message, `v‖r‖s`
+fn signature_bytes(signature: Option<&Signature>) -> Vec<u8> {
+    signature
+        .map(|signature| [&signature.v[..], &signature.r[..], &signature.s[..]].concat())
+        .unwrap_or_default()
+}
+
+/// Id of the signed hash, SHA-256 over the hash and the signature bytes.
+/// The order hash doesn't cover the signature, so a forged copy of an order
+/// can't take the id of the signed one
+fn signed_id(kind: &str, hash: &H256, signature: Option<&Signature>) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(hash.as_bytes());
+    hasher.update(signature_bytes(signature));
+    format!("{}:{}", kind, hex::encode(hasher.finalize()))
+}
+
+/// Content-addressed id of the gossip message, the same on every node.
+/// Orders and cancels are identified by the canonical order hash and the signature, so the same
+/// signed order published in different envelopes is deduplicated, anything else by SHA-256 of the data
+pub fn message_id(message: &GossipsubMessage) -> MessageId {
+    let payload = GossipEnvelope::decode(Cursor::new(&message.data))
+        .ok()
+        .and_then(|envelope| envelope.payload);
+
+    let id = match payload {
+        Some(Payload::OrderCommitment(order_commitment)) => order::order_hash(&order_commitment)
+            .ok()
+            .map(|hash| signed_id("order", &hash, order_commitment.signature.as_ref())),
+        Some(Payload::Cancel(cancel)) => cancel.order_commitment
+            .as_ref()
+            .and_then(|order_commitment| order::order_hash(order_commitment).ok())
+            .map(|hash| signed_id("cancel", &hash, cancel.signature.as_ref())),
+        None => None,
+    };
+
+    let id = id.unwrap_or_else(|| format!("sha256:{}", hex::encode(Sha256::digest(&message.data))));
+    MessageId::from(id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn gossip_message(data: Vec<u8>) -> GossipsubMessage {
+        GossipsubMessage {
+            source: None,
+            data,
+            sequence_number: None,
+            topic: IdentTopic::new(ORDERS_TOPIC).hash(),
+        }
+    }
+
+    fn order_commitment(signature: Option<Signature>) -> OrderCommitment {
+        OrderCommitment {
+            signer: "0xa9D93b8154A38e35Bd003294393cC29398825996".to_string(),
+            taker: "0x0000000000000000000000000000000000000000".to_string(),
+            contract_address: "0x3B92d83A02465F52F80d1265aBaDeF29056dcB48".to_string(),
+            token_address: "0x2B31AF0a19c5a01a0ca5A300b85977aFE8bf4acA".to_string(),
+            nft_id: "1".to_string(),
+            gossiper: "0x0000000000000000000000000000000000000000".to_string(),
+            price: "12313".to_string(),
+            order_type: 1,
+            signature,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn forged_copy_gets_another_id() {
+        let signature = Signature { v: vec![27], r: vec![1; 32], s: vec![2; 32] };
+        let forged_signature = Signature { v: vec![27], r: vec![3; 32], s: vec![2; 32] };
+
+        let signed = message_id(&gossip_message(order_envelope(&Handshake::default(), order_commitment(Some(signature.clone())))));
+        let relayed = message_id(&gossip_message(order_envelope(&Handshake::default(), order_commitment(Some(signature)))));
+        let unsigned = message_id(&gossip_message(order_envelope(&Handshake::default(), order_commitment(None))));
+        let forged = message_id(&gossip_message(order_envelope(&Handshake::default(), order_commitment(Some(forged_signature)))));
+
+        assert_eq!(signed, relayed);
+        assert_ne!(signed, unsigned);
+        assert_ne!(signed, forged);
+    }
+
+    #[test]
+    fn forged_cancel_gets_another_id() {
+        let cancel = |signature| CancelOrder {
+            order_id: String::new(),
+            signature,
+            order_commitment: Some(order_commitment(None)),
+        };
+
+        let signed = message_id(&gossip_message(cancel_envelope(&Handshake::default(), cancel(Some(Signature { v: vec![27], r: vec![1; 32], s: vec![2; 32] })))));
+        let unsigned = message_id(&gossip_message(cancel_envelope(&Handshake::default(), cancel(None))));
+
+        assert_ne!(signed, unsigned);
+    }
+}
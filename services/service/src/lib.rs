@@ -1,4 +1,9 @@
 // Declare and implement Rocket endpoints
 pub mod behaviour;
+mod bootnodes;
+mod gossip;
+mod protocol;
+mod scoring;
 mod service;
+mod validation;
 pub use crate::service::*;
\ No newline at end of file
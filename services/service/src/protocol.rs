@@ -0,0 +1,105 @@
+use std::io;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use futures::{AsyncRead, AsyncWrite, AsyncWriteExt};
+use libp2p::core::upgrade::{read_length_prefixed, write_length_prefixed, ProtocolName};
+use libp2p::request_response::{
+    ProtocolSupport, RequestResponse, RequestResponseCodec, RequestResponseConfig,
+};
+use prost::Message;
+use common_types::p2p::{PeerRequest, PeerResponse};
+
+/// Requests and responses larger than this are refused
+pub const MAX_PEER_MESSAGE_SIZE: usize = 4 * 1024 * 1024;
+
+/// Number of orders requested per sync page
+pub const SYNC_PAGE_SIZE: u32 = 100;
+
+/// How often the order set is reconciled with a random peer
+pub const ANTI_ENTROPY_INTERVAL: Duration = Duration::from_secs(60);
+
+/// How often deferred syncs are retried, e.g until the validator set is loaded
+pub const SYNC_RETRY_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Upper bound of orders or cancels transferred in one reconciliation request
+pub const MAX_RECONCILE_ENTRIES: usize = 1000;
+
+/// Timeout of a single request to a peer
+pub const PEER_REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Direct request-response protocol between nodes
+#[derive(Debug, Clone)]
+pub struct NodeProtocol();
+
+impl ProtocolName for NodeProtocol {
+    fn protocol_name(&self) -> &[u8] {
+        b"/nft-market/rpc/1"
+    }
+}
+
+/// Length prefixed protobuf codec of the `NodeProtocol`
+#[derive(Debug, Clone)]
+pub struct NodeCodec();
+
+/// Decode protobuf message read from the stream
+fn decode<M: Message + Default>(bytes: Vec<u8>) -> io::Result<M> {
+    M::decode(bytes.as_slice()).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+/// Encode protobuf message to be written to the stream
+fn encode<M: Message>(message: &M) -> Vec<u8> {
+    let mut buff = Vec::new();
+    buff.reserve(message.encoded_len());
+    message.encode(&mut buff).unwrap();
+    buff
+}
+
+#[async_trait]
+impl RequestResponseCodec for NodeCodec {
+    type Protocol = NodeProtocol;
+    type Request = PeerRequest;
+    type Response = PeerResponse;
+
+    async fn read_request<T>(&mut self, _: &NodeProtocol, io: &mut T) -> io::Result<PeerRequest>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        decode(read_length_prefixed(io, MAX_PEER_MESSAGE_SIZE).await?)
+    }
+
+    async fn read_response<T>(&mut self, _: &NodeProtocol, io: &mut T) -> io::Result<PeerResponse>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        decode(read_length_prefixed(io, MAX_PEER_MESSAGE_SIZE).await?)
+    }
+
+    async fn write_request<T>(&mut self, _: &NodeProtocol, io: &mut T, request: PeerRequest) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        write_length_prefixed(io, encode(&request)).await?;
+        io.close().await
+    }
+
+    async fn write_response<T>(&mut self, _: &NodeProtocol, io: &mut T, response: PeerResponse) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        write_length_prefixed(io, encode(&response)).await?;
+        io.close().await
+    }
+}
+
+/// Create request-response behaviour for the `NodeProtocol`
+pub fn node_protocol() -> RequestResponse<NodeCodec> {
+    let mut config = RequestResponseConfig::default();
+    config.set_request_timeout(PEER_REQUEST_TIMEOUT);
+
+    RequestResponse::new(
+        NodeCodec(),
+        std::iter::once((NodeProtocol(), ProtocolSupport::Full)),
+        config,
+    )
+}
@@ -0,0 +1,153 @@
+use std::collections::HashMap;
+use std::time::Duration;
+
+use libp2p::PeerId;
+use libp2p::gossipsub::{PeerScoreParams, PeerScoreThresholds, TopicScoreParams};
+
+use crate::gossip;
+
+/// Peers below this score are disconnected
+pub const DISCONNECT_THRESHOLD: f64 = -80.0;
+
+/// How often peer scores are checked for disconnection
+pub const SCORE_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Known orders and cancels a peer may resend before it's penalised.
+/// Honest peers resend orders that arrived here through sync or a direct push first
+pub const DUPLICATE_PENALTY_THRESHOLD: f64 = 5.0;
+
+/// Decay of the duplicate count per `SCORE_CHECK_INTERVAL`
+pub const DUPLICATE_PENALTY_DECAY: f64 = 0.9;
+
+/// Score parameters of a topic.
+/// Invalid messages weigh the most, mesh delivery penalties are disabled
+/// since order traffic is low and bursty
+fn topic_params(topic_weight: f64, invalid_message_weight: f64) -> TopicScoreParams {
+    TopicScoreParams {
+        topic_weight,
+        time_in_mesh_weight: 0.01,
+        time_in_mesh_quantum: Duration::from_secs(1),
+        time_in_mesh_cap: 100.0,
+        first_message_deliveries_weight: 1.0,
+        first_message_deliveries_decay: 0.9,
+        first_message_deliveries_cap: 50.0,
+        mesh_message_deliveries_weight: 0.0,
+        mesh_failure_penalty_weight: 0.0,
+        invalid_message_deliveries_weight: invalid_message_weight,
+        invalid_message_deliveries_decay: 0.99,
+        ..Default::default()
+    }
+}
+
+/// Peer scoring parameters for all topics of the node
+pub fn peer_score_params() -> (PeerScoreParams, PeerScoreThresholds) {
+    let mut topics = HashMap::new();
+    for topic in gossip::topics() {
+        topics.insert(topic.hash(), topic_params(1.0, -10.0));
+    }
+
+    let params = PeerScoreParams {
+        topics,
+        topic_score_cap: 100.0,
+        behaviour_penalty_weight: -10.0,
+        behaviour_penalty_threshold: 6.0,
+        behaviour_penalty_decay: 0.99,
+        // duplicate penalties, see `DuplicatePenalties`
+        app_specific_weight: 1.0,
+        ..Default::default()
+    };
+
+    let thresholds = PeerScoreThresholds {
+        gossip_threshold: -10.0,
+        publish_threshold: -50.0,
+        graylist_threshold: DISCONNECT_THRESHOLD,
+        accept_px_threshold: 10.0,
+        opportunistic_graft_threshold: 5.0,
+    };
+
+    (params, thresholds)
+}
+
+/// Application specific score of the duplicate count
+fn duplicate_score(count: f64) -> f64 {
+    let excess = (count - DUPLICATE_PENALTY_THRESHOLD).max(0.0);
+    -excess * excess
+}
+
+/// Counts known orders and cancels resent by the peers.
+/// Gossipsub drops copies of a message id it has seen by itself, so these are copies resent
+/// once the id left its cache. The count above the threshold is squared into the application score
+#[derive(Debug, Default)]
+pub struct DuplicatePenalties {
+    counts: HashMap<PeerId, f64>,
+}
+
+impl DuplicatePenalties {
+    /// Count a duplicate sent by the peer, returns its new application score
+    pub fn record(&mut self, peer_id: PeerId) -> f64 {
+        let count = self.counts.entry(peer_id).or_default();
+        *count += 1.0;
+        duplicate_score(*count)
+    }
+
+    /// Decay the counts, returns the new application score of every counted peer.
+    /// Peers with less than one duplicate left are forgotten
+    pub fn decay(&mut self) -> Vec<(PeerId, f64)> {
+        let mut scores = Vec::with_capacity(self.counts.len());
+        for (peer_id, count) in self.counts.iter_mut() {
+            *count *= DUPLICATE_PENALTY_DECAY;
+            scores.push((*peer_id, duplicate_score(*count)));
+        }
+        self.counts.retain(|_, count| *count >= 1.0);
+        scores
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use libp2p::identity::Keypair;
+    use libp2p::gossipsub::{Gossipsub, GossipsubConfig, MessageAuthenticity};
+    use libp2p::swarm::NetworkBehaviour;
+
+    fn scored_gossipsub(peer_id: &PeerId) -> Gossipsub {
+        let mut gossipsub = Gossipsub::new(
+            MessageAuthenticity::Signed(Keypair::generate_ed25519()),
+            GossipsubConfig::default(),
+        ).unwrap();
+        let (params, thresholds) = peer_score_params();
+        gossipsub.with_peer_score(params, thresholds).unwrap();
+        gossipsub.inject_connected(peer_id);
+        gossipsub
+    }
+
+    #[test]
+    fn repeat_sender_loses_score() {
+        let peer_id = PeerId::random();
+        let mut gossipsub = scored_gossipsub(&peer_id);
+        let mut duplicates = DuplicatePenalties::default();
+
+        // a few duplicates are tolerated
+        for _ in 0..DUPLICATE_PENALTY_THRESHOLD as usize {
+            gossipsub.set_application_score(&peer_id, duplicates.record(peer_id));
+        }
+        assert_eq!(gossipsub.peer_score(&peer_id), Some(0.0));
+
+        let mut score = 0.0;
+        for _ in 0..10 {
+            gossipsub.set_application_score(&peer_id, duplicates.record(peer_id));
+            let new_score = gossipsub.peer_score(&peer_id).unwrap();
+            assert!(new_score < score);
+            score = new_score;
+        }
+        assert!(score < DISCONNECT_THRESHOLD);
+
+        // the penalty wears off
+        for _ in 0..20 {
+            for (peer_id, score) in duplicates.decay() {
+                gossipsub.set_application_score(&peer_id, score);
+            }
+        }
+        assert_eq!(gossipsub.peer_score(&peer_id), Some(0.0));
+    }
+}
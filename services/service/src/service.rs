@@ -1,5 +1,5 @@
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use async_std::channel::{Receiver, Sender, unbounded};
 use async_std::stream;
 use async_std::prelude::*;
@@ -8,65 +8,90 @@ use futures::{StreamExt, select};
 use db::rocks::{RocksDB};
 pub use db::rocks::{DB};
 use libp2p::core::muxing::StreamMuxerBox;
-use libp2p::swarm::SwarmEvent;
+use libp2p::swarm::{AddressScore, SwarmEvent};
 use libp2p::{Transport};
 use libp2p::core::transport::{Boxed};
 use libp2p::{PeerId, Swarm, gossipsub::{IdentTopic, GossipsubEvent}, Multiaddr};
 use libp2p::identity::Keypair;
 use libp2p::kad::{Record, record::{Key}, Quorum};
-use log::{error};
-use prost::Message;
 
 use crate::behaviour::{NodeBehaviour};
+use crate::gossip;
+use crate::bootnodes::{Bootnodes, DIAL_CHECK_INTERVAL, peer_id_of};
+use crate::protocol::{ANTI_ENTROPY_INTERVAL, SYNC_RETRY_INTERVAL};
+use crate::scoring::{DISCONNECT_THRESHOLD, SCORE_CHECK_INTERVAL};
 use common_types::{
-    AppStorage, Gossip, NetworkMessage, OrderCommitment, 
-    Storage, Error, NetworkEvent
+    AppStorage, NetworkMessage, OrderCommitment, 
+    Storage, Error, NetworkEvent, NetworkEvents
 };
+use common_types::p2p::Handshake;
 
 pub struct P2pService {
     swarm: Swarm<NodeBehaviour>,
     db: Arc<RocksDB>,
     sender_in: Sender<NetworkMessage>,
-    sender_out: Sender<NetworkEvent>,
     receiver_in: Receiver<NetworkMessage>,
-    receiver_out: Receiver<NetworkEvent>,
+    events: NetworkEvents,
+    bootnodes: Bootnodes,
+    sync: bool,
 }
 
 impl P2pService {
-    pub fn new(
-        local_key: Keypair,
-        db: Arc<RocksDB>,
-        bootnode: Option<String>
-    ) -> Self {
+    pub fn new(node: &P2pNode, db: Arc<RocksDB>, handshake: Handshake) -> Result<Self, Error> {
+        let local_key = &node.local_key;
         // create a peer id
         let local_peer_id = PeerId::from(local_key.public());
         let transport = create_transport(local_key.clone());
+        let bootnodes = Bootnodes::new(node.bootnodes.clone());
+        // create network message senders/receivers
+        let (sender_in, receiver_in) = unbounded();
+        let events = NetworkEvents::default();
         // instantiate swarm from our NodeBehaviour
         let mut swarm = Swarm::new(
             transport, 
-            NodeBehaviour::new(&local_key, local_peer_id.clone(), bootnode, db.clone()), 
+            NodeBehaviour::new(
+                local_key,
+                local_peer_id,
+                &bootnodes.addresses(),
+                db.clone(),
+                handshake,
+                events.clone(),
+                node.agent_version.clone(),
+            ), 
             local_peer_id
         );
 
-        // listen on
-        Swarm::listen_on(&mut swarm, "/ip4/0.0.0.0/tcp/0".parse().unwrap()).unwrap();
+        // listen on any free port unless configured
+        let listen_addresses = if node.listen_addresses.is_empty() {
+            vec!["/ip4/0.0.0.0/tcp/0".parse().unwrap()]
+        } else {
+            node.listen_addresses.clone()
+        };
+        for address in listen_addresses {
+            if let Err(e) = Swarm::listen_on(&mut swarm, address.clone()) {
+                return Err(Error::Other(format!("couldn't listen on {}: {}", address, e)));
+            }
+        }
+
+        // announced to peers, e.g by identify and kademlia
+        if let Some(address) = node.external_address.clone() {
+            println!("[SERVICE] External address: {}", address);
+            swarm.add_external_address(address, AddressScore::Infinite);
+        }
 
         if let Err(e) = swarm.behaviour_mut().bootstrap() {
             println!("[KAD] Couldn't bootstrap from kademlia {}", e);
         }
 
-        // create network message senders/receivers
-        let (sender_in, receiver_in) = unbounded();
-        let (sender_out, receiver_out) = unbounded();
-        
-        P2pService {
+        Ok(P2pService {
             swarm,
             db,
             sender_in, 
             receiver_in,
-            sender_out,
-            receiver_out,
-        }
+            events,
+            bootnodes,
+            sync: node.sync,
+        })
     }
 
     /// Launches the p2p service
@@ -75,14 +100,91 @@ impl P2pService {
         let mut swarm_stream = self.swarm.fuse();
         let mut network_stream = self.receiver_in.fuse();
         
-        // let mut interval = async_std::stream::int
+        let mut score_check = stream::interval(SCORE_CHECK_INTERVAL).fuse();
+        let mut anti_entropy = stream::interval(ANTI_ENTROPY_INTERVAL).fuse();
+        let mut sync_retry = stream::interval(SYNC_RETRY_INTERVAL).fuse();
+        let mut dial_check = stream::interval(DIAL_CHECK_INTERVAL).fuse();
+        let mut bootnodes = self.bootnodes;
+        // peer id of the current slot processor, if it can be dialed
+        let mut processor: Option<PeerId> = None;
+        
         loop {
             select! {
+                _ = score_check.next() => {
+                    // graylisted peers are disconnected
+                    let swarm = swarm_stream.get_mut();
+                    swarm.behaviour_mut().decay_duplicate_penalties();
+                    let graylisted: Vec<PeerId> = swarm
+                        .behaviour()
+                        .peer_scores()
+                        .into_iter()
+                        .filter(|(_, score)| *score < DISCONNECT_THRESHOLD)
+                        .map(|(peer_id, _)| peer_id)
+                        .collect();
+                    for peer_id in graylisted {
+                        println!("[SERVICE] Disconnecting misbehaving peer {:?}", peer_id);
+                        if swarm.disconnect_peer_id(peer_id).is_err() {
+                            println!("[SERVICE] Peer {:?} is not connected", peer_id);
+                        }
+                    }
+                },
+                _ = dial_check.next() => {
+                    let swarm = swarm_stream.get_mut();
+                    for address in bootnodes.due(Instant::now()) {
+                        println!("[SERVICE] Dialing bootnode {}", address);
+                        if let Err(e) = swarm.dial_addr(address.clone()) {
+                            println!("[SERVICE] Couldn't dial bootnode {}: {:?}", address, e);
+                            if let Some(peer_id) = peer_id_of(&address) {
+                                bootnodes.schedule_redial(&peer_id, Instant::now());
+                            }
+                        }
+                    }
+                },
+                _ = sync_retry.next() => {
+                    swarm_stream.get_mut().behaviour_mut().retry_syncs();
+                },
+                _ = anti_entropy.next() => {
+                    // repairs orders and cancels lost in gossip, e.g during partitions
+                    swarm_stream.get_mut().behaviour_mut().start_anti_entropy();
+                },
                 swarm_event = swarm_stream.next() => match swarm_event {
                     Some(event) => match event {
                         SwarmEvent::NewListenAddr {address, .. } => {
                             println!("[SERVICE] Your node's address: {:?}/{:?}", address, swarm_stream.get_mut().local_peer_id().to_string());
                         },
+                        SwarmEvent::ConnectionEstablished { peer_id, endpoint, num_established } => {
+                            bootnodes.connected(&peer_id);
+                            if num_established.get() == 1 {
+                                // bind the peer to its validator address
+                                swarm_stream.get_mut().behaviour_mut().start_handshake(peer_id);
+                                self.events.publish(NetworkEvent::PeerConnected {
+                                    peer_id,
+                                    address: endpoint.get_remote_address().clone(),
+                                });
+                            }
+                            swarm_stream
+                                .get_mut()
+                                .behaviour_mut()
+                                .peer_connected(peer_id, endpoint.get_remote_address().clone());
+                            // catch up with orders gossiped before the node joined
+                            if self.sync {
+                                swarm_stream.get_mut().behaviour_mut().start_sync(peer_id);
+                            }
+                        },
+                        SwarmEvent::ConnectionClosed { peer_id, endpoint, num_established, .. } => {
+                            swarm_stream
+                                .get_mut()
+                                .behaviour_mut()
+                                .peer_disconnected(&peer_id, endpoint.get_remote_address(), num_established);
+                            if num_established == 0 {
+                                bootnodes.schedule_redial(&peer_id, Instant::now());
+                                self.events.publish(NetworkEvent::PeerDisconnected { peer_id });
+                            }
+                        },
+                        SwarmEvent::UnreachableAddr { peer_id, address, error, attempts_remaining: 0 } => {
+                            println!("[SERVICE] Couldn't reach {:?} at {}: {:?}", peer_id, address, error);
+                            bootnodes.schedule_redial(&peer_id, Instant::now());
+                        },
                         _ => { continue; }
                     },
                     None => { break; }
@@ -92,22 +194,15 @@ impl P2pService {
                         NetworkMessage::GossipMessage { source, topic, message } => {
                             println!("[SERVICE] Got message! {:?}", message);
                         }
-                        NetworkMessage::PingRequest { peer_id } => {
-                            let gossip = swarm_stream.get_mut().behaviour_mut().gossip(IdentTopic::new("ping"), "ping ping");
-                            println!("[SERVICE] Got ping request! {:?}", gossip);
-                            match gossip {
-                                Ok(gossip) => {
-                                    println!("[SERVICE] Gossiping ping");
-                                },
-                                Err(e) => {
-                                    println!("[SERVICE] Couldn't send gossip message {:?}", e);
-                                }
-                            };
+                        NetworkMessage::PingRequest { peer_id, sender } => {
+                            println!("[SERVICE] Got ping request for {:?}", peer_id);
+                            swarm_stream.get_mut().behaviour_mut().ping(peer_id, sender);
                         }
                         NetworkMessage::NewSlot {address, slot} => {
                             println!("[SERVICE] New slot gossip {:?}", slot);
                             // set slot in db
                             self.db.set_slot_number(slot);
+                            self.events.publish(NetworkEvent::SlotChanged { address, slot });
                             
                             // kademlia record
                             let record = Record {
@@ -116,55 +211,72 @@ impl P2pService {
                                 publisher: None,
                                 expires: None
                             };
-                            // insert new slot in the distributed DHT
-                            swarm_stream.get_mut()
+                            // insert new slot in the distributed DHT,
+                            // the slot is stored locally already, so a failed put isn't fatal
+                            let put_record = swarm_stream.get_mut()
                                 .behaviour_mut()
-                                .kademlia.put_record(record, Quorum::One)
-                                .expect("Couldn't put record in kad");
+                                .kademlia.put_record(record, Quorum::One);
+                            if let Err(e) = put_record {
+                                println!("[SERVICE] Couldn't put slot record in kad: {:?}", e);
+                            }
                         }
                         NetworkMessage::NewOrderCommitment {
                             source,
                             order_commitment
                         } => {
                             println!("[SERVCE] Received new order commitment {:?}", order_commitment);
-                            // encode order commitment
-                            let mut buff = Vec::new();
-                            buff.reserve(order_commitment.encoded_len());
-                            order_commitment.encode(&mut buff).unwrap();
-
-                            println!("[SERVICE] Gossiping order commitment: {:?}", buff.clone());
+                            let envelope = gossip::order_envelope(
+                                swarm_stream.get_ref().behaviour().handshake(),
+                                order_commitment.clone(),
+                            );
 
+                            let topic = IdentTopic::new(gossip::ORDERS_TOPIC);
                             let gossip_order = swarm_stream
                                 .get_mut()
                                 .behaviour_mut()
                                 .gossip(
-                                    IdentTopic::new("order_commitment"),
-                                    buff
+                                    topic.clone(),
+                                    envelope
                                 );
                             // attempt to gossip order commitment
-                            match gossip_order {
-                                Ok(gossip) => {
+                            let gossiped = match gossip_order {
+                                Ok(_) => {
                                     println!("[SERVICE] Gossiping order commitment:");
+                                    true
                                 },
                                 Err(e) => {
                                     println!("[SERVICE] Couldn't send gossip message {:?}", e);
+                                    false
                                 }
                             };
 
-                            if self.db.put_order_commitment(order_commitment).is_err() {
-                                error!("Couldn't store order commitment in db");
-                            };
+                            // the processor gets the order even if it's not subscribed to the topic,
+                            // but only once: a subscribed processor already got the published copy
+                            if let Some(peer_id) = processor {
+                                let behaviour = swarm_stream.get_mut().behaviour_mut();
+                                if !gossiped || !behaviour.is_topic_peer(&peer_id, &topic) {
+                                    behaviour.forward_order(&peer_id, order_commitment.clone());
+                                }
+                            }
+
+                            // the order was stored by the RPC already
+                            self.events.publish(NetworkEvent::OrderReceived { source, order_commitment });
                         },
                         NetworkMessage::RemoveOrder {
-                            id
+                            cancel
                         } => {
-                            println!("[SERVICE] Removing order {:?}", id);
+                            println!("[SERVICE] Removing order {:?}", cancel.order_id);
+                            let envelope = gossip::cancel_envelope(
+                                swarm_stream.get_ref().behaviour().handshake(),
+                                cancel.clone(),
+                            );
+
                             let gossip_order = swarm_stream
                                 .get_mut()
                                 .behaviour_mut()
                                 .gossip(
-                                    IdentTopic::new("cancel_order"),
-                                    id.as_bytes()
+                                    IdentTopic::new(gossip::CANCELS_TOPIC),
+                                    envelope
                                 );
                             // attempt to gossip order commitment
                             match gossip_order {
@@ -176,13 +288,39 @@ impl P2pService {
                                 }
                             };
 
-                            // if self.db.delete_order_commitment(&id).is_err() {
-                            //     error!("Couldn't delete order commitment in db");
-                            // };
+                            // the cancel was applied by the RPC already
+                            let source = *swarm_stream.get_mut().local_peer_id();
+                            self.events.publish(NetworkEvent::OrderCancelled { source, cancel });
+                        },
+                        NetworkMessage::PeerScores { sender } => {
+                            let scores = swarm_stream.get_mut().behaviour().peer_scores();
+                            if sender.send(scores).is_err() {
+                                println!("[SERVICE] Couldn't send peer scores");
+                            }
                         },
-                        NetworkMessage::CurrentProcessor {address} => {
-                            println!("Current processor {:?}", address);
-                            // self.db.set_processor_address(address);
+                        NetworkMessage::Peers { sender } => {
+                            let peers = swarm_stream.get_mut().behaviour().peers();
+                            if sender.send(peers).is_err() {
+                                println!("[SERVICE] Couldn't send peers");
+                            }
+                        },
+                        NetworkMessage::CurrentProcessor { address, node_url } => {
+                            if self.db.current_processor() != address {
+                                println!("[SERVICE] Current processor {:?} at {}", address, node_url);
+                            }
+                            if let Err(e) = self.db.set_processor_address(&address) {
+                                println!("[SERVICE] Couldn't store current processor: {}", e);
+                            }
+                            processor = dial_processor(swarm_stream.get_mut(), &node_url);
+                        }
+                        NetworkMessage::OrdersMatched { order_commitments } => {
+                            match self.db.remove_matched_orders(&order_commitments) {
+                                Ok(hashes) => {
+                                    println!("[SERVICE] Removed {} matched orders", hashes.len());
+                                    self.events.publish(NetworkEvent::OrdersMatched { hashes });
+                                },
+                                Err(e) => println!("[SERVICE] Error removing matched orders: {}", e),
+                            }
                         }
                         _ => println!("Unhandled request"),
                     }
@@ -192,8 +330,14 @@ impl P2pService {
         }
     }
 
+    /// New subscriber of the network events
     pub fn network_receiver(&self) -> Receiver<NetworkEvent> {
-        self.receiver_out.clone()
+        self.events.subscribe()
+    }
+
+    /// Handle to subscribe to the network events after the service is launched
+    pub fn network_events(&self) -> NetworkEvents {
+        self.events.clone()
     }
 
     pub fn network_sender(&self) -> Sender<NetworkMessage> {
@@ -203,11 +347,23 @@ impl P2pService {
 
 }
 
-/// emit event to the network
-pub async fn emit_event(sender: &Sender<NetworkEvent>, event: NetworkEvent) {
-    if sender.send(event).await.is_err() {
-        error!("Couldn't send event to node behaviour");
+/// Dial the slot processor unless it's already connected, returns its peer id.
+/// Processors registered without a multiaddr node URL, and the node itself, are skipped
+fn dial_processor(swarm: &mut Swarm<NodeBehaviour>, node_url: &str) -> Option<PeerId> {
+    let address = node_url.parse::<Multiaddr>().ok()?;
+    let peer_id = peer_id_of(&address)?;
+    if &peer_id == swarm.local_peer_id() {
+        return None;
+    }
+
+    if !swarm.is_connected(&peer_id) {
+        swarm.behaviour_mut().kademlia.add_address(&peer_id, address);
+        match swarm.dial(&peer_id) {
+            Ok(_) => println!("[SERVICE] Dialing processor {:?}", peer_id),
+            Err(e) => println!("[SERVICE] Couldn't dial processor {:?}: {:?}", peer_id, e),
+        }
     }
+    Some(peer_id)
 }
 
 /// Create a new transport for the p2p service communication
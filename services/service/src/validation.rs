@@ -0,0 +1,275 @@
+use std::io::{Cursor};
+
+use libp2p::PeerId;
+use libp2p::gossipsub::{GossipsubMessage, MessageAcceptance};
+use prost::Message;
+use common_types::{Address, AppStorage, CancelOrder, H256, OrderCommitment, order};
+use common_types::handshake::verify_handshake;
+use common_types::p2p::{GossipEnvelope, Handshake};
+use common_types::p2p::gossip_envelope::{MessageType, Payload};
+
+use crate::gossip::{self, GOSSIP_VERSION};
+
+/// Messages larger than this are rejected without decoding
+pub const MAX_GOSSIP_MESSAGE_SIZE: usize = 64 * 1024;
+
+/// Envelopes timestamped further in the future are dropped
+pub const MAX_CLOCK_DRIFT_SECS: u64 = 5 * 60;
+
+/// Gossip message that passed validation
+#[derive(Debug)]
+pub(crate) enum ValidMessage {
+    Order(OrderCommitment),
+    Cancel(CancelOrder),
+}
+
+/// Outcome of the validation that is reported back to gossipsub.
+/// `Reject` penalises the sender, `Ignore` just drops the message
+/// unless it's a `duplicate`, which counts towards the duplicate penalty of the sender
+#[derive(Debug)]
+pub(crate) struct Invalid {
+    pub acceptance: MessageAcceptance,
+    pub reason: String,
+    pub duplicate: bool,
+}
+
+impl Invalid {
+    fn reject(reason: impl Into<String>) -> Self {
+        Invalid { acceptance: MessageAcceptance::Reject, reason: reason.into(), duplicate: false }
+    }
+
+    fn ignore(reason: impl Into<String>) -> Self {
+        Invalid { acceptance: MessageAcceptance::Ignore, reason: reason.into(), duplicate: false }
+    }
+
+    fn duplicate(reason: impl Into<String>) -> Self {
+        Invalid { acceptance: MessageAcceptance::Ignore, reason: reason.into(), duplicate: true }
+    }
+}
+
+/// Validate gossip message before it's accepted and forwarded.
+/// `bound` is the address the message source proved with its handshake, if it's connected to the node
+pub(crate) fn validate_message<DB: AppStorage>(
+    db: &DB,
+    message: &GossipsubMessage,
+    bound: Option<&Address>,
+) -> Result<ValidMessage, Invalid> {
+    let topic = message.topic.as_str();
+    let data = message.data.as_slice();
+    if data.len() > MAX_GOSSIP_MESSAGE_SIZE {
+        return Err(Invalid::reject(format!("message too large: {} bytes", data.len())));
+    }
+
+    let envelope = GossipEnvelope::decode(Cursor::new(data))
+        .map_err(|e| Invalid::reject(format!("undecodable envelope: {}", e)))?;
+
+    // peers of another protocol version are incompatible
+    if envelope.version != GOSSIP_VERSION {
+        return Err(Invalid::reject(format!("unsupported gossip version: {}", envelope.version)));
+    }
+    if envelope.timestamp > order::unix_now() + MAX_CLOCK_DRIFT_SECS {
+        return Err(Invalid::ignore("envelope is timestamped in the future"));
+    }
+    check_sender(&envelope, message.source.as_ref(), bound)?;
+
+    let message_type = MessageType::from_i32(envelope.message_type).unwrap_or(MessageType::Unknown);
+    let expected_topic = gossip::topic(message_type).map(|expected| expected.hash().into_string());
+    if expected_topic.as_deref() != Some(topic) {
+        return Err(Invalid::reject(format!("{:?} message on topic {}", message_type, topic)));
+    }
+
+    match (message_type, envelope.payload) {
+        (MessageType::Order, Some(Payload::OrderCommitment(order_commitment))) => {
+            validate_order(db, order_commitment).map(ValidMessage::Order)
+        },
+        (MessageType::Cancel, Some(Payload::Cancel(cancel))) => {
+            validate_cancel(db, cancel).map(ValidMessage::Cancel)
+        },
+        _ => Err(Invalid::reject(format!("payload doesn't match message type {:?}", message_type))),
+    }
+}
+
+/// Check that the envelope sender is the address the message source is bound to.
+/// The envelope carries the handshake signature of the sender, so publishers that aren't connected
+/// to the node are checked as well, and connected ones have to use the address of their handshake
+fn check_sender(envelope: &GossipEnvelope, source: Option<&PeerId>, bound: Option<&Address>) -> Result<Address, Invalid> {
+    let source = source.ok_or_else(|| Invalid::reject("message has no source"))?;
+    let handshake = Handshake {
+        address: envelope.sender.clone(),
+        signature: envelope.sender_signature.clone(),
+    };
+    let sender = verify_handshake(source, &handshake)
+        .map_err(|e| Invalid::reject(format!("sender isn't bound to the source: {}", e)))?;
+
+    match bound {
+        Some(bound) if *bound != sender => Err(Invalid::reject(
+            format!("sender {:?} differs from {:?} the source is bound to", sender, bound)
+        )),
+        _ => Ok(sender),
+    }
+}
+
+/// Check fields, signature, expiry and duplicates of the order
+fn validate_order<DB: AppStorage>(db: &DB, order_commitment: OrderCommitment) -> Result<OrderCommitment, Invalid> {
+    let hash = check_order(db, &order_commitment)?;
+    // honest peers re-deliver orders we got through sync, anti-entropy or a direct push
+    if db.order_commitment(&hash).map_or(false, |stored| stored.is_some()) {
+        return Err(Invalid::duplicate("order is already known"));
+    }
+
+    Ok(order_commitment)
+}
+
+/// Check signature, expiry and cancellation of the order, returns its hash
+pub(crate) fn check_order<DB: AppStorage>(db: &DB, order_commitment: &OrderCommitment) -> Result<H256, Invalid> {
+    let hash = order::order_hash(order_commitment)
+        .map_err(|e| Invalid::reject(e.to_string()))?;
+    order::verify_signature(order_commitment)
+        .map_err(|e| Invalid::reject(e.to_string()))?;
+
+    if order::is_expired(order_commitment, order::unix_now()) {
+        return Err(Invalid::reject("order is expired"));
+    }
+    // the peer may not have received the cancel yet
+    if db.is_cancelled(&hash).unwrap_or(false) {
+        return Err(Invalid::ignore("order is cancelled"));
+    }
+
+    Ok(hash)
+}
+
+/// Check signature and duplicates of the cancel
+fn validate_cancel<DB: AppStorage>(db: &DB, cancel: CancelOrder) -> Result<CancelOrder, Invalid> {
+    let hash = check_cancel(&cancel)?;
+    if db.is_cancelled(&hash).unwrap_or(false) {
+        return Err(Invalid::duplicate("order is already cancelled"));
+    }
+
+    Ok(cancel)
+}
+
+/// Check that the cancel is signed by the order signer, returns the order hash
+pub(crate) fn check_cancel(cancel: &CancelOrder) -> Result<H256, Invalid> {
+    order::verify_cancel(cancel)
+        .map_err(|e| Invalid::reject(e.to_string()))
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use async_std::task;
+    use libp2p::gossipsub::IdentTopic;
+    use common_types::{LocalWallet, Signature, Signer, Uuid};
+    use common_types::handshake::sign_handshake;
+    use db::rocks::RocksDB;
+
+    const SIGNER_KEY: &str = "9221bd3e2a1ccc039b6f7779c26b3a60560421641f1a550d0767e09ace8a77fb";
+
+    pub(crate) fn open_db() -> RocksDB {
+        RocksDB::open(std::env::temp_dir().join(format!("validation-{}", Uuid::new_v4()))).unwrap()
+    }
+
+    pub(crate) fn signed_order() -> OrderCommitment {
+        let wallet: LocalWallet = SIGNER_KEY.parse().unwrap();
+        let mut order_commitment = OrderCommitment {
+            signer: format!("{:?}", wallet.address()),
+            taker: format!("{:?}", Address::zero()),
+            contract_address: "0x3B92d83A02465F52F80d1265aBaDeF29056dcB48".to_string(),
+            token_address: "0x2B31AF0a19c5a01a0ca5A300b85977aFE8bf4acA".to_string(),
+            nft_id: "1".to_string(),
+            gossiper: format!("{:?}", Address::zero()),
+            price: "12313".to_string(),
+            order_type: 1,
+            ..Default::default()
+        };
+        let signature = wallet.sign_hash(order::hash_for_signature(&order_commitment).unwrap(), false);
+        let mut r = [0u8; 32];
+        let mut s = [0u8; 32];
+        signature.r.to_big_endian(&mut r);
+        signature.s.to_big_endian(&mut s);
+        order_commitment.signature = Some(Signature { v: vec![signature.v as u8], r: r.to_vec(), s: s.to_vec() });
+        order_commitment
+    }
+
+    fn handshake(peer_id: &PeerId) -> Handshake {
+        let wallet: LocalWallet = SIGNER_KEY.parse().unwrap();
+        task::block_on(sign_handshake(&wallet, peer_id)).unwrap()
+    }
+
+    /// Order published by `source` with the handshake
+    fn order_message(source: PeerId, handshake: &Handshake, order_commitment: OrderCommitment) -> GossipsubMessage {
+        GossipsubMessage {
+            source: Some(source),
+            data: gossip::order_envelope(handshake, order_commitment),
+            sequence_number: None,
+            topic: IdentTopic::new(gossip::ORDERS_TOPIC).hash(),
+        }
+    }
+
+    /// Order published by a peer with a valid handshake
+    fn published_order(order_commitment: OrderCommitment) -> GossipsubMessage {
+        let source = PeerId::random();
+        order_message(source, &handshake(&source), order_commitment)
+    }
+
+    #[test]
+    fn new_order_is_accepted() {
+        let db = open_db();
+        let message = published_order(signed_order());
+
+        assert!(matches!(validate_message(&db, &message, None), Ok(ValidMessage::Order(_))));
+    }
+
+    #[test]
+    fn known_order_is_ignored() {
+        let db = open_db();
+        let order_commitment = signed_order();
+        db.put_order_commitment(order_commitment.clone()).unwrap();
+        let message = published_order(order_commitment);
+
+        let invalid = validate_message(&db, &message, None).unwrap_err();
+        assert!(matches!(invalid.acceptance, MessageAcceptance::Ignore), "{}", invalid.reason);
+        assert!(invalid.duplicate);
+    }
+
+    #[test]
+    fn matched_order_is_ignored() {
+        let db = open_db();
+        let order_commitment = signed_order();
+        db.put_order_commitment(order_commitment.clone()).unwrap();
+        db.remove_matched_orders(std::slice::from_ref(&order_commitment)).unwrap();
+        let message = published_order(order_commitment);
+
+        let invalid = validate_message(&db, &message, None).unwrap_err();
+        assert!(matches!(invalid.acceptance, MessageAcceptance::Ignore), "{}", invalid.reason);
+    }
+
+    #[test]
+    fn forged_order_is_rejected() {
+        let db = open_db();
+        let mut order_commitment = signed_order();
+        order_commitment.price = "1".to_string();
+        let message = published_order(order_commitment);
+
+        let invalid = validate_message(&db, &message, None).unwrap_err();
+        assert!(matches!(invalid.acceptance, MessageAcceptance::Reject), "{}", invalid.reason);
+    }
+
+    #[test]
+    fn sender_of_another_peer_is_rejected() {
+        let db = open_db();
+        let message = order_message(PeerId::random(), &handshake(&PeerId::random()), signed_order());
+
+        let invalid = validate_message(&db, &message, None).unwrap_err();
+        assert!(matches!(invalid.acceptance, MessageAcceptance::Reject), "{}", invalid.reason);
+    }
+
+    #[test]
+    fn sender_differing_from_the_bound_address_is_rejected() {
+        let db = open_db();
+        let message = published_order(signed_order());
+
+        let invalid = validate_message(&db, &message, Some(&Address::zero())).unwrap_err();
+        assert!(matches!(invalid.acceptance, MessageAcceptance::Reject), "{}", invalid.reason);
+    }
+}
@@ -2,7 +2,7 @@
 fn main() -> Result<(), Box<dyn std::error::Error>> {
     tonic_build::configure()
         .build_server(true)
-        .compile(&["proto/node_rpc.proto"], &["proto"])
+        .compile(&["proto/node_rpc.proto", "proto/p2p.proto"], &["proto"])
         .unwrap();
     Ok(())
 }
\ No newline at end of file
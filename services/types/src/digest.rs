@@ -0,0 +1,324 @@
+use std::collections::HashSet;
+
+use ethers::utils::keccak256;
+
+use crate::H256;
+use crate::p2p::DigestBucket;
+
+/// Number of leaves of the order set digest,
+/// hashes are bucketed by their first byte
+pub const DIGEST_BUCKETS: usize = 256;
+
+/// Upper bound of hashes in one digest response,
+/// so a page stays well under the size limit of peer messages
+pub const MAX_DIGEST_PAGE_HASHES: usize = 100_000;
+
+/// Bucket of the order or tombstone hash
+pub fn bucket(hash: &H256) -> usize {
+    hash.as_bytes()[0] as usize
+}
+
+/// Parse hashes received from a peer, malformed ones are dropped
+pub fn hashes_from_bytes(values: &[Vec<u8>]) -> Vec<H256> {
+    values
+        .iter()
+        .filter(|value| value.len() == 32)
+        .map(|value| H256::from_slice(value))
+        .collect()
+}
+
+/// Encode hashes for a peer
+pub fn hashes_to_bytes(hashes: &[H256]) -> Vec<Vec<u8>> {
+    hashes.iter().map(|hash| hash.as_bytes().to_vec()).collect()
+}
+
+/// Hashes of open orders and tombstones falling into one bucket
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct BucketEntries {
+    pub orders: Vec<H256>,
+    pub tombstones: Vec<H256>,
+    /// Orders settled by the Marketplace. Their tombstones are unsigned, so peers can't verify them,
+    /// and every node learns about the match from the chain instead. Left out of the digest
+    pub matched: Vec<H256>,
+}
+
+impl BucketEntries {
+    /// Leaf of the digest, zero for an empty bucket.
+    /// Entries are expected in storage (key) order
+    pub fn digest(&self) -> H256 {
+        if self.orders.is_empty() && self.tombstones.is_empty() {
+            return H256::zero();
+        }
+        let mut bytes = Vec::with_capacity(32 * (self.orders.len() + self.tombstones.len()) + 1);
+        for hash in &self.orders {
+            bytes.extend_from_slice(hash.as_bytes());
+        }
+        // separates orders from tombstones, so cancelling an order changes the leaf
+        bytes.push(0xff);
+        for hash in &self.tombstones {
+            bytes.extend_from_slice(hash.as_bytes());
+        }
+        H256::from(keccak256(bytes))
+    }
+
+    /// Entries `other` doesn't know about.
+    /// Orders already cancelled or matched by `other` are not reported
+    pub fn missing_from(&self, other: &BucketEntries) -> BucketEntries {
+        let known_orders: HashSet<&H256> = other.orders.iter().collect();
+        let known_tombstones: HashSet<&H256> = other.tombstones.iter().chain(other.matched.iter()).collect();
+
+        BucketEntries {
+            orders: self.orders
+                .iter()
+                .filter(|hash| !known_orders.contains(hash) && !known_tombstones.contains(hash))
+                .cloned()
+                .collect(),
+            tombstones: self.tombstones
+                .iter()
+                .filter(|hash| !known_tombstones.contains(hash))
+                .cloned()
+                .collect(),
+            matched: Vec::new(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.orders.is_empty() && self.tombstones.is_empty()
+    }
+
+    /// Parse the bucket received from a peer
+    pub fn from_proto(bucket: &DigestBucket) -> Self {
+        BucketEntries {
+            orders: hashes_from_bytes(&bucket.order_hashes),
+            tombstones: hashes_from_bytes(&bucket.tombstone_hashes),
+            matched: Vec::new(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.orders.len() + self.tombstones.len()
+    }
+
+    /// Encode the bucket with the given index for a peer
+    pub fn to_proto(&self, index: usize) -> DigestBucket {
+        DigestBucket {
+            index: index as u32,
+            order_hashes: hashes_to_bytes(&self.orders),
+            tombstone_hashes: hashes_to_bytes(&self.tombstones),
+            truncated: false,
+        }
+    }
+
+    /// Encode at most `limit` hashes of the bucket, tombstones first
+    fn to_truncated_proto(&self, index: usize, limit: usize) -> DigestBucket {
+        let tombstones = &self.tombstones[..self.tombstones.len().min(limit)];
+        let orders = &self.orders[..self.orders.len().min(limit - tombstones.len())];
+        DigestBucket {
+            index: index as u32,
+            order_hashes: hashes_to_bytes(orders),
+            tombstone_hashes: hashes_to_bytes(tombstones),
+            truncated: true,
+        }
+    }
+}
+
+/// Page of the `differing` buckets, starting with the first one not below `start`,
+/// with at most `max_hashes` hashes. A page has at least one bucket,
+/// so a bucket larger than the page is truncated.
+/// Returns the page and the bucket the next page starts with, `None` for the last page
+pub fn digest_page(
+    local: &[BucketEntries],
+    differing: &[usize],
+    start: usize,
+    max_hashes: usize,
+) -> (Vec<DigestBucket>, Option<usize>) {
+    let mut page = Vec::new();
+    let mut hashes = 0;
+
+    for &index in differing.iter().filter(|index| **index >= start) {
+        let entries = match local.get(index) {
+            Some(entries) => entries,
+            None => continue,
+        };
+        if hashes + entries.len() > max_hashes {
+            if page.is_empty() {
+                page.push(entries.to_truncated_proto(index, max_hashes));
+                return (page, differing.iter().find(|next| **next > index).cloned());
+            }
+            return (page, Some(index));
+        }
+        hashes += entries.len();
+        page.push(entries.to_proto(index));
+    }
+
+    (page, None)
+}
+
+/// Entries of the received page missing locally, and local entries of its buckets missing on the peer.
+/// Local entries of a truncated bucket aren't reported, since the peer may have the rest of the bucket
+pub fn reconcile_page(local: &[BucketEntries], page: &[DigestBucket]) -> (BucketEntries, BucketEntries) {
+    let mut missing = BucketEntries::default();
+    let mut extra = BucketEntries::default();
+
+    for bucket in page {
+        let local = match local.get(bucket.index as usize) {
+            Some(local) => local,
+            None => continue,
+        };
+        let remote = BucketEntries::from_proto(bucket);
+        let bucket_missing = remote.missing_from(local);
+        missing.orders.extend(bucket_missing.orders);
+        missing.tombstones.extend(bucket_missing.tombstones);
+        if !bucket.truncated {
+            let bucket_extra = local.missing_from(&remote);
+            extra.orders.extend(bucket_extra.orders);
+            extra.tombstones.extend(bucket_extra.tombstones);
+        }
+    }
+
+    (missing, extra)
+}
+
+/// Two level Merkle tree over the open order set:
+/// a leaf per bucket and the root over all leaves
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OrderSetDigest {
+    pub root: H256,
+    pub buckets: Vec<H256>,
+}
+
+impl OrderSetDigest {
+    /// Build the digest from all `DIGEST_BUCKETS` buckets
+    pub fn from_buckets(entries: &[BucketEntries]) -> Self {
+        let buckets: Vec<H256> = entries.iter().map(|entries| entries.digest()).collect();
+        Self::from_leaves(buckets)
+    }
+
+    /// Build the digest from the leaves, e.g received from a peer
+    pub fn from_leaves(buckets: Vec<H256>) -> Self {
+        let mut bytes = Vec::with_capacity(32 * buckets.len());
+        for leaf in &buckets {
+            bytes.extend_from_slice(leaf.as_bytes());
+        }
+        OrderSetDigest { root: H256::from(keccak256(bytes)), buckets }
+    }
+
+    /// Indexes of the buckets that differ from the other digest
+    pub fn differing_buckets(&self, other: &OrderSetDigest) -> Vec<usize> {
+        if self.root == other.root {
+            return Vec::new();
+        }
+        (0..DIGEST_BUCKETS)
+            .filter(|i| self.buckets.get(*i) != other.buckets.get(*i))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hash(first: u8, last: u8) -> H256 {
+        let mut bytes = [0u8; 32];
+        bytes[0] = first;
+        bytes[31] = last;
+        H256::from(bytes)
+    }
+
+    /// Buckets of the order and tombstone hashes
+    fn buckets(orders: &[H256], tombstones: &[H256]) -> Vec<BucketEntries> {
+        let mut buckets = vec![BucketEntries::default(); DIGEST_BUCKETS];
+        for order in orders {
+            buckets[bucket(order)].orders.push(*order);
+        }
+        for tombstone in tombstones {
+            buckets[bucket(tombstone)].tombstones.push(*tombstone);
+        }
+        buckets
+    }
+
+    #[test]
+    fn empty_sets_do_not_differ() {
+        let empty = buckets(&[], &[]);
+        let digest = OrderSetDigest::from_buckets(&empty);
+
+        assert!(digest.differing_buckets(&OrderSetDigest::from_buckets(&empty)).is_empty());
+        assert!(empty[0].missing_from(&empty[0]).is_empty());
+    }
+
+    #[test]
+    fn equal_sets_do_not_differ() {
+        let local = buckets(&[hash(1, 1), hash(2, 1)], &[hash(3, 1)]);
+        let remote = buckets(&[hash(1, 1), hash(2, 1)], &[hash(3, 1)]);
+
+        let digest = OrderSetDigest::from_buckets(&local);
+        assert!(digest.differing_buckets(&OrderSetDigest::from_buckets(&remote)).is_empty());
+        for (local, remote) in local.iter().zip(remote.iter()) {
+            assert!(local.missing_from(remote).is_empty());
+        }
+    }
+
+    #[test]
+    fn disjoint_sets_differ_in_every_occupied_bucket() {
+        let local = buckets(&[hash(1, 1)], &[hash(2, 1)]);
+        let remote = buckets(&[hash(1, 2), hash(7, 1)], &[]);
+
+        let differing = OrderSetDigest::from_buckets(&local)
+            .differing_buckets(&OrderSetDigest::from_buckets(&remote));
+        assert_eq!(differing, vec![1, 2, 7]);
+
+        assert_eq!(local[1].missing_from(&remote[1]).orders, vec![hash(1, 1)]);
+        assert_eq!(remote[1].missing_from(&local[1]).orders, vec![hash(1, 2)]);
+        assert_eq!(local[2].missing_from(&remote[2]).tombstones, vec![hash(2, 1)]);
+        assert_eq!(remote[7].missing_from(&local[7]).orders, vec![hash(7, 1)]);
+    }
+
+    #[test]
+    fn cancelled_orders_are_not_missing() {
+        let local = buckets(&[hash(1, 1)], &[]);
+        let remote = buckets(&[], &[hash(1, 1)]);
+
+        assert!(local[1].missing_from(&remote[1]).is_empty());
+        assert_eq!(remote[1].missing_from(&local[1]).tombstones, vec![hash(1, 1)]);
+    }
+
+    #[test]
+    fn matched_orders_are_left_out() {
+        let mut local = buckets(&[], &[]);
+        local[1].matched.push(hash(1, 1));
+        let remote = buckets(&[hash(1, 1)], &[]);
+
+        let differing = OrderSetDigest::from_buckets(&local)
+            .differing_buckets(&OrderSetDigest::from_buckets(&buckets(&[], &[])));
+        assert!(differing.is_empty());
+
+        let (missing, extra) = reconcile_page(&local, &[remote[1].to_proto(1)]);
+        assert!(missing.is_empty());
+        assert!(extra.is_empty());
+    }
+
+    #[test]
+    fn digest_is_paged_by_bucket() {
+        let local = buckets(&[hash(1, 1), hash(1, 2), hash(2, 1), hash(3, 1)], &[]);
+        let differing = vec![1, 2, 3];
+
+        let (page, next) = digest_page(&local, &differing, 0, 3);
+        assert_eq!(page.iter().map(|bucket| bucket.index).collect::<Vec<_>>(), vec![1, 2]);
+        assert_eq!(next, Some(3));
+
+        let (page, next) = digest_page(&local, &differing, 3, 3);
+        assert_eq!(page.iter().map(|bucket| bucket.index).collect::<Vec<_>>(), vec![3]);
+        assert_eq!(next, None);
+    }
+
+    #[test]
+    fn oversized_bucket_is_truncated() {
+        let local = buckets(&[hash(1, 1), hash(1, 2), hash(2, 1)], &[]);
+
+        let (page, next) = digest_page(&local, &[1, 2], 0, 1);
+        assert_eq!(page.len(), 1);
+        assert!(page[0].truncated);
+        assert_eq!(page[0].order_hashes.len(), 1);
+        assert_eq!(next, Some(2));
+    }
+}
@@ -0,0 +1,48 @@
+use std::convert::TryFrom;
+
+use ethers::signers::{LocalWallet, Signer};
+use ethers::types::{RecoveryMessage, Signature as EthSignature};
+use libp2p::PeerId;
+
+use crate::{Address, Error};
+use crate::p2p::Handshake;
+
+/// Message signed by the node wallet, binds the peer id to the Ethereum address
+pub fn handshake_message(peer_id: &PeerId) -> Vec<u8> {
+    let mut message = b"nft-market handshake:".to_vec();
+    message.extend_from_slice(&peer_id.to_bytes());
+    message
+}
+
+/// Sign the handshake of the node with its wallet
+pub async fn sign_handshake(wallet: &LocalWallet, peer_id: &PeerId) -> Result<Handshake, Error> {
+    let signature = wallet
+        .sign_message(handshake_message(peer_id))
+        .await
+        .map_err(|e| Error::InvalidSignature(e.to_string()))?;
+
+    Ok(Handshake {
+        address: format!("{:?}", wallet.address()),
+        signature: signature.to_vec(),
+    })
+}
+
+/// Verify the handshake received from the peer,
+/// returns the Ethereum address the peer id is bound to
+pub fn verify_handshake(peer_id: &PeerId, handshake: &Handshake) -> Result<Address, Error> {
+    let address = handshake.address
+        .parse::<Address>()
+        .map_err(|_| Error::InvalidSignature(format!("invalid handshake address: {}", handshake.address)))?;
+    let signature = EthSignature::try_from(handshake.signature.as_slice())
+        .map_err(|e| Error::InvalidSignature(e.to_string()))?;
+
+    let recovered = signature
+        .recover(RecoveryMessage::Data(handshake_message(peer_id)))
+        .map_err(|e| Error::InvalidSignature(e.to_string()))?;
+    if recovered != address {
+        return Err(Error::InvalidSignature(format!(
+            "handshake is signed by {:?}, not {:?}", recovered, address
+        )));
+    }
+    Ok(address)
+}
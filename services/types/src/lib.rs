@@ -1,33 +1,58 @@
 use rocksdb::DBIterator;
 pub use rocksdb::{
     DB, Options, WriteBatch, 
-    WriteOptions, IteratorMode,
+    WriteOptions, IteratorMode, Direction,
 };
 
-pub use libp2p::{PeerId};
+pub use libp2p::{Multiaddr, PeerId};
 pub use libp2p::gossipsub::{IdentTopic};
-pub use ethers::types::{Address};
+pub use ethers::types::{Address, H256, U256};
 pub use ethers::signers::{LocalWallet, Signer};
+pub use ethers::utils::hex;
 pub use uuid::Uuid;
 pub use libp2p::kad::record::*;
 
 use tonic::codec::{Decoder, Encoder};
 use std::fmt::Display;
-use async_std::channel::Sender;
-use std::sync::Arc;
+use async_std::channel::{Receiver, Sender, bounded};
+use futures::channel::oneshot;
+use std::sync::{Arc, Mutex};
 use std::io::{Cursor};
+use std::time::Duration;
 use thiserror::{Error};
 use prost::Message;
 
+pub mod digest;
+pub mod handshake;
 pub mod node;
+pub mod order;
+pub mod query;
 
-pub use node_rpc::{ OrderCommitment, OrderCommitmentList, EmptyRequest };
+pub use node_rpc::{
+    OrderCommitment, OrderCommitmentList, EmptyRequest, Signature,
+    OrderFilter, OrderQuery, OrderSubscription, OrderEvent,
+    OrderId, TokenId, CancelOrder, PeerScore, PeerScoreList, PeerInfo, PeerList,
+    PingRequest, PingResult, PingResponse,
+};
+pub use node_rpc::order_event::Kind as OrderEventKind;
+use node_rpc::order_commitment;
 pub use node_rpc::node_rpc_server::{ NodeRpc, NodeRpcServer };
 
 pub mod node_rpc {
     tonic::include_proto!("node_rpc");
 }
 
+/// Messages of the direct peer-to-peer protocol
+pub mod p2p {
+    tonic::include_proto!("p2p");
+}
+
+/// How often expired orders are pruned from the storage
+pub const ORDER_PRUNE_INTERVAL: Duration = Duration::from_secs(30);
+
+/// How long order events are kept for resuming subscribers
+pub const ORDER_EVENT_RETENTION: Duration = Duration::from_secs(24 * 60 * 60);
+
 /// Fallback main processor
 pub const DEFAULT_MAIN_PROCESSOR: &str = "0x5542b9d2a0afc227f917eec349f1312fbe7c35cb";
 
@@ -40,28 +65,90 @@ pub struct GRPCConfig {
     pub address: Address,
 }
 
-#[derive(Debug)]
+/// Network activity published by the p2p service to its subscribers
+#[derive(Debug, Clone)]
 pub enum NetworkEvent {
-    PubSubMessage {
+    /// Order received from the peer, or created on this node when `source` is the local peer, and stored
+    OrderReceived {
         source: PeerId,
-        message: Gossip,
+        order_commitment: OrderCommitment,
     },
-    PingRequest {
+    /// Cancel received from the peer, or made on this node when `source` is the local peer, and applied
+    OrderCancelled {
         source: PeerId,
+        cancel: CancelOrder,
+    },
+    /// First connection to the peer is established
+    PeerConnected {
+        peer_id: PeerId,
+        address: Multiaddr,
+    },
+    /// Last connection to the peer is closed
+    PeerDisconnected {
+        peer_id: PeerId,
+    },
+    /// New slot of the auction protocol
+    SlotChanged {
+        address: Address,
+        slot: u128,
+    },
+    /// Orders settled by the marketplace and removed
+    OrdersMatched {
+        hashes: Vec<H256>,
+    },
+    /// Expired orders pruned from the storage
+    OrdersExpired {
+        hashes: Vec<H256>,
     },
 }
 
+impl NetworkEvent {
+    /// Check if the event changed the order book, so there are new order events in the storage
+    pub fn changes_orders(&self) -> bool {
+        matches!(
+            self,
+            NetworkEvent::OrderReceived { .. }
+                | NetworkEvent::OrderCancelled { .. }
+                | NetworkEvent::OrdersMatched { .. }
+                | NetworkEvent::OrdersExpired { .. }
+        )
+    }
+}
 
-pub type SlotNumber = u128;
+/// Events not read by a subscriber are dropped once its buffer is full
+pub const NETWORK_EVENT_BUFFER: usize = 1024;
 
-#[derive(Debug)]
-pub enum Gossip {
-    // List(Vec<String>),
-    OrderCommitment(OrderCommitment),
-    OrderCommitmentList(Vec<OrderCommitment>),
-    Ping(),
+/// Subscribers of the network events.
+/// Every subscriber has its own channel and receives every published event
+#[derive(Debug, Clone, Default)]
+pub struct NetworkEvents {
+    subscribers: Arc<Mutex<Vec<Sender<NetworkEvent>>>>,
 }
 
+impl NetworkEvents {
+    /// New subscriber, receives events published after the call
+    pub fn subscribe(&self) -> Receiver<NetworkEvent> {
+        let (sender, receiver) = bounded(NETWORK_EVENT_BUFFER);
+        self.subscribers.lock().unwrap().push(sender);
+        receiver
+    }
+
+    /// Send the event to every subscriber.
+    /// Never blocks the publisher: a subscriber with a full buffer misses the event,
+    /// and subscribers that dropped their receiver are removed
+    pub fn publish(&self, event: NetworkEvent) {
+        self.subscribers
+            .lock()
+            .unwrap()
+            .retain(|sender| match sender.try_send(event.clone()) {
+                Ok(_) => true,
+                Err(e) => !e.is_closed(),
+            });
+    }
+}
+
+pub type SlotNumber = u128;
+
 #[derive(Debug)]
 pub enum NetworkMessage {
     GossipMessage {
@@ -69,23 +156,39 @@ pub enum NetworkMessage {
         topic: IdentTopic,
         message: Vec<u8>
     },
+    /// Ping the peer, or all connected peers if `peer_id` is `None`
     PingRequest {
-        peer_id: PeerId,
+        peer_id: Option<PeerId>,
+        sender: oneshot::Sender<Vec<PingResult>>,
     },
+    /// Order created over RPC and stored already, to be gossiped
     NewOrderCommitment {
         source: PeerId,
         order_commitment: OrderCommitment,
     },
     RemoveOrder {
-        id: String,
+        cancel: CancelOrder,
     },
+    /// Winner of the current slot, sent every block
     CurrentProcessor {
         address: Address,
+        /// node URL registered in the auction protocol
+        node_url: String,
+    },
+    PeerScores {
+        sender: oneshot::Sender<Vec<(PeerId, f64)>>,
+    },
+    Peers {
+        sender: oneshot::Sender<Vec<PeerInfo>>,
     },
     NewSlot {
         address: Address,
         slot: u128,
     },
+    /// Orders settled by a `Match` of the marketplace, they're removed and tombstoned
+    OrdersMatched {
+        order_commitments: Vec<OrderCommitment>,
+    },
 }
 
 
@@ -94,6 +197,9 @@ pub enum NetworkMessage {
 pub enum Error {
     #[error(transparent)]
     Database(#[from] rocksdb::Error),
+    InvalidOrder(String),
+    InvalidSignature(String),
+    InvalidQuery(String),
     Other(String)
 }
 
@@ -101,6 +207,9 @@ impl Display for Error {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             Error::Database(e) => write!(f, "Database error: {}", e),
+            Error::InvalidOrder(s) => write!(f, "Invalid order: {}", s),
+            Error::InvalidSignature(s) => write!(f, "Invalid signature: {}", s),
+            Error::InvalidQuery(s) => write!(f, "Invalid query: {}", s),
             Error::Other(s) => write!(f, "Other error: {}", s)
         }
     }
@@ -112,41 +221,156 @@ impl From<Error> for String {
     }
 }
 
+/// Column families of the storage
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Column {
+    /// Order commitments keyed by order hash
+    Orders,
+    /// Order hashes keyed by (contract, nft id)
+    NftIndex,
+    /// Order hashes keyed by signer
+    SignerIndex,
+    /// Order hashes keyed by (payment token, price)
+    PriceIndex,
+    /// Hashes of expiring orders keyed by expiry time
+    ExpiryIndex,
+    /// Order book changes keyed by (time, order hash)
+    OrderEvents,
+    /// Signed cancels keyed by order hash
+    Tombstones,
+    /// Slot related values
+    Slots,
+    /// Node metadata
+    Meta,
+    /// Kademlia records and provider records
+    Kademlia,
+    /// Addresses of the validators registered in the auction protocol
+    Validators,
+}
+
+impl Column {
+    /// All column families, created when the storage is opened
+    pub const ALL: [Column; 11] = [
+        Column::Orders,
+        Column::NftIndex,
+        Column::SignerIndex,
+        Column::PriceIndex,
+        Column::ExpiryIndex,
+        Column::OrderEvents,
+        Column::Tombstones,
+        Column::Slots,
+        Column::Meta,
+        Column::Kademlia,
+        Column::Validators,
+    ];
+
+    /// Name of the column family
+    pub fn name(&self) -> &'static str {
+        match self {
+            Column::Orders => "orders",
+            Column::NftIndex => "orders_by_nft",
+            Column::SignerIndex => "orders_by_signer",
+            Column::PriceIndex => "orders_by_price",
+            Column::ExpiryIndex => "orders_by_expiry",
+            Column::OrderEvents => "order_events",
+            Column::Tombstones => "tombstones",
+            Column::Slots => "slots",
+            Column::Meta => "meta",
+            Column::Kademlia => "kademlia",
+            Column::Validators => "validators",
+        }
+    }
+}
+
+/// Single operation of the atomic batch
+#[derive(Debug, Clone)]
+pub enum BatchOp {
+    Put(Column, Vec<u8>, Vec<u8>),
+    Delete(Column, Vec<u8>),
+}
+
+/// Set of writes that are applied atomically
+#[derive(Debug, Clone, Default)]
+pub struct Batch {
+    pub ops: Vec<BatchOp>,
+    /// Encoded order events, keyed with the next event sequence numbers on commit
+    pub events: Vec<Vec<u8>>,
+}
+
+impl Batch {
+    /// Put a value in the batch
+    pub fn put(&mut self, column: Column, key: impl AsRef<[u8]>, value: impl AsRef<[u8]>) {
+        self.ops.push(BatchOp::Put(column, key.as_ref().to_vec(), value.as_ref().to_vec()));
+    }
+
+    /// Delete a value in the batch
+    pub fn delete(&mut self, column: Column, key: impl AsRef<[u8]>) {
+        self.ops.push(BatchOp::Delete(column, key.as_ref().to_vec()));
+    }
+
+    /// Append an order event to the feed, see `query::event_key`
+    pub fn push_event(&mut self, value: Vec<u8>) {
+        self.events.push(value);
+    }
+}
+
+/// Encode typed object for the storage
+fn encode<T: Message>(value: &T) -> Vec<u8> {
+    let mut buff = Vec::new();
+    buff.reserve(value.encoded_len());
+    value.encode(&mut buff).unwrap();
+    buff
+}
+
+/// Append order event to the batch
+fn push_order_event(batch: &mut Batch, kind: OrderEventKind, order_commitment: &OrderCommitment) {
+    let event = OrderEvent {
+        kind: kind as i32,
+        order_commitment: Some(order_commitment.clone()),
+        timestamp: order::unix_now(),
+        cursor: String::new(),
+    };
+    batch.push_event(encode(&event));
+}
+
 /// Interface for Key-Value storage
 pub trait Storage {
     /// Get a value from the storage
-    fn read<K>(&self, key: K) -> Result<Option<Vec<u8>>, Error>
+    fn read<K>(&self, column: Column, key: K) -> Result<Option<Vec<u8>>, Error>
     where
         K: AsRef<[u8]>;
     
     /// Put a value into the storage
-    fn write<K, V>(&self, key: K, value: V) -> Result<(), Error>
+    fn write<K, V>(&self, column: Column, key: K, value: V) -> Result<(), Error>
     where
         K: AsRef<[u8]>,
         V: AsRef<[u8]>;
     
     /// Delete a value from the storage
-    fn delete<K>(&self, key: K) -> Result<(), Error>
+    fn delete<K>(&self, column: Column, key: K) -> Result<(), Error>
     where
         K: AsRef<[u8]>;
 
 
     /// Check if a key exists in the storage
-    fn contains<K>(&self, key: K) -> Result<bool, Error>
+    fn contains<K>(&self, column: Column, key: K) -> Result<bool, Error>
     where
         K: AsRef<[u8]>;
     
     
-    /// Create an iterator over the storage
-    fn iterator(&self, mode: IteratorMode) -> DBIterator;
+    /// Create an iterator over the column
+    fn iterator(&self, column: Column, mode: IteratorMode) -> DBIterator;
+
+    /// Apply all operations of the batch atomically
+    fn commit(&self, batch: Batch) -> Result<(), Error>;
 }
 
 pub trait AppStorage: Storage {
     // Get typed object from the storage
-    fn get<T>(&self, key: &str) -> Result<Option<T>, Error>
+    fn get<T>(&self, column: Column, key: impl AsRef<[u8]>) -> Result<Option<T>, Error>
     where
         T: Message + Default {
-        let value = self.read(key)?;
+        let value = self.read(column, key)?;
         match value {
             Some(v) => {
                 let decoded = T::decode(
@@ -159,19 +383,38 @@ pub trait AppStorage: Storage {
     }
 
     /// Put typed object into the storage
-    fn put<T>(&self, key: &str, value: &T) -> Result<(), Error>
+    fn put<T>(&self, column: Column, key: impl AsRef<[u8]>, value: &T) -> Result<(), Error>
     where
         T: Message + Default {
-        let mut buff = Vec::new();
-        buff.reserve(value.encoded_len());
-        value.encode(&mut buff).unwrap();
-        self.write(key, buff)
+        self.write(column, key, encode(value))
     }
 
-    /// Put order commitment in the storage
-    fn put_order_commitment(&self, order_commitment: OrderCommitment) -> Result<OrderCommitment, Box<Error>> {
-        let key = format!("order_commitment_{}", order_commitment.order_id);
-        let result = match self.put(&key, &order_commitment) {
+    /// Get order commitment by its hash
+    fn order_commitment(&self, hash: &H256) -> Result<Option<OrderCommitment>, Box<Error>> {
+        Ok(self.get::<OrderCommitment>(Column::Orders, hash.as_bytes())?)
+    }
+
+    /// Put order commitment in the storage together with its index entries.
+    /// Order is keyed by its canonical hash, which also becomes its `order_id`
+    fn put_order_commitment(&self, mut order_commitment: OrderCommitment) -> Result<OrderCommitment, Box<Error>> {
+        let hash = order::order_hash(&order_commitment)?;
+        order_commitment.order_id = order::order_id(&hash);
+
+        // cancelled orders can't be brought back
+        if self.is_cancelled(&hash)? {
+            return Err(Box::new(Error::InvalidOrder(format!("order {:?} is cancelled", hash))));
+        }
+
+        let mut batch = Batch::default();
+        if !self.contains(Column::Orders, hash.as_bytes())? {
+            push_order_event(&mut batch, OrderEventKind::Created, &order_commitment);
+        }
+        batch.put(Column::Orders, hash.as_bytes(), encode(&order_commitment));
+        for (column, key) in query::index_keys(&order_commitment, &hash)? {
+            batch.put(column, key, b"");
+        }
+
+        let result = match self.commit(batch) {
             Ok(_) => {
                 Ok(order_commitment)
             },
@@ -183,18 +426,18 @@ pub trait AppStorage: Storage {
         result
     }
 
-    /// Get all order commitments in the storage
+    /// Get all order commitments in the storage that are not expired
     fn retrieve_order_commitments(&self) -> Result<OrderCommitmentList, Box<Error>> {
-        let iter = self.iterator(IteratorMode::Start);
+        let iter = self.iterator(Column::Orders, IteratorMode::Start);
         let mut commitments = OrderCommitmentList::default();
+        let now = order::unix_now();
         
-        // iterate through the database and add all the order commitments to the list
-        for (key, value) in iter {
-            // check if b"order_commitment" is subset of key
-            if key.starts_with(b"order_commitment") {
-                let commitment = OrderCommitment::decode(
-                    &mut Cursor::new(value.to_vec().as_slice())
-                ).unwrap();
+        // iterate through the orders and add all the order commitments to the list
+        for (_, value) in iter {
+            let commitment = OrderCommitment::decode(
+                &mut Cursor::new(value.to_vec().as_slice())
+            ).unwrap();
+            if !order::is_expired(&commitment, now) {
                 commitments.order_commitments.push(commitment);
             }
         }
@@ -202,11 +445,144 @@ pub trait AppStorage: Storage {
         Ok(commitments)
     }
 
-    /// Delete order commitment from the storage
-    fn delete_order_commitment(&self, order_id: &str) -> Result<String, Box<Error>> {
-        let key = format!("order_commitment_{}", order_id);
-        let result = match self.delete(&key) {
-            Ok(_) => Ok(key),
+    /// Hashes of stored orders and tombstones bucketed for the anti-entropy digest.
+    /// Unsigned tombstones of matched orders are kept apart, peers can't verify them
+    fn order_set(&self) -> Result<Vec<digest::BucketEntries>, Box<Error>> {
+        let mut buckets = vec![digest::BucketEntries::default(); digest::DIGEST_BUCKETS];
+
+        for (key, _) in self.iterator(Column::Orders, IteratorMode::Start) {
+            let hash = query::hash_from_key(&key)?;
+            buckets[digest::bucket(&hash)].orders.push(hash);
+        }
+        for (key, value) in self.iterator(Column::Tombstones, IteratorMode::Start) {
+            let hash = query::hash_from_key(&key)?;
+            let cancel = CancelOrder::decode(&mut Cursor::new(value.as_ref())).unwrap();
+            let entries = &mut buckets[digest::bucket(&hash)];
+            if cancel.signature.is_some() {
+                entries.tombstones.push(hash);
+            } else {
+                entries.matched.push(hash);
+            }
+        }
+
+        Ok(buckets)
+    }
+
+    /// Digest of the stored orders and tombstones
+    fn order_set_digest(&self) -> Result<digest::OrderSetDigest, Box<Error>> {
+        Ok(digest::OrderSetDigest::from_buckets(&self.order_set()?))
+    }
+
+    /// Get all open order commitments for the NFT
+    fn orders_for_token(&self, contract: Address, nft_id: U256) -> Result<OrderCommitmentList, Box<Error>> {
+        let selector = query::OrderSelector {
+            contract: Some(contract),
+            nft_id: Some(nft_id),
+            ..Default::default()
+        };
+        let range = selector.key_range();
+        let now = order::unix_now();
+
+        let mut commitments = OrderCommitmentList::default();
+        for (key, _) in self.iterator(range.column, IteratorMode::From(range.start.as_slice(), Direction::Forward)) {
+            if !range.contains(&key) {
+                break;
+            }
+            if let Some(commitment) = self.order_commitment(&query::hash_from_key(&key)?)? {
+                if !order::is_expired(&commitment, now) {
+                    commitments.order_commitments.push(commitment);
+                }
+            }
+        }
+
+        Ok(commitments)
+    }
+
+    /// Get a page of order commitments matching the query.
+    /// Served from the secondary index that fits the filter best
+    fn query_order_commitments(&self, order_query: &OrderQuery) -> Result<OrderCommitmentList, Box<Error>> {
+        let selector = query::OrderSelector::parse(order_query.filter.as_ref())?;
+        let range = selector.key_range();
+        let start = range.start_from(&order_query.cursor)?;
+        let page_size = query::page_size(order_query.page_size);
+        let now = order::unix_now();
+
+        let mut commitments = OrderCommitmentList::default();
+        let iter = self.iterator(range.column, IteratorMode::From(start.as_slice(), Direction::Forward));
+
+        for (key, _) in iter {
+            if !range.contains(&key) {
+                break;
+            }
+            // cursor points to the last order of the previous page
+            if !order_query.cursor.is_empty() && key.as_ref() == start.as_slice() {
+                continue;
+            }
+
+            let hash = query::hash_from_key(&key)?;
+            let commitment = match self.order_commitment(&hash)? {
+                Some(commitment) => commitment,
+                None => continue,
+            };
+            if order::is_expired(&commitment, now) || !selector.matches(&commitment) {
+                continue;
+            }
+
+            commitments.order_commitments.push(commitment);
+            if commitments.order_commitments.len() == page_size {
+                commitments.next_cursor = hex::encode(&key);
+                break;
+            }
+        }
+
+        Ok(commitments)
+    }
+
+    /// Remove all order commitments that are expired at `now`.
+    /// Only the expired range of the expiry index is walked.
+    /// Returns hashes of the pruned orders
+    fn prune_expired_orders(&self, now: u64) -> Result<Vec<H256>, Box<Error>> {
+        let mut expired = Vec::new();
+        let mut batch = Batch::default();
+        let range = query::expired_range(now);
+
+        for (key, _) in self.iterator(range.column, IteratorMode::Start) {
+            if !range.contains(&key) {
+                break;
+            }
+            let hash = query::hash_from_key(&key)?;
+            if self.contains(Column::Orders, hash.as_bytes())? {
+                self.push_order_removal(&mut batch, &hash, OrderEventKind::Expired)?;
+                expired.push(hash);
+            } else {
+                batch.delete(range.column, key);
+            }
+        }
+
+        self.commit(batch)?;
+        Ok(expired)
+    }
+
+    /// Add removal of the order commitment and its index entries to the batch,
+    /// recording the reason in the order feed
+    fn push_order_removal(&self, batch: &mut Batch, hash: &H256, kind: OrderEventKind) -> Result<(), Box<Error>> {
+        batch.delete(Column::Orders, hash.as_bytes());
+        if let Some(commitment) = self.order_commitment(hash)? {
+            for (column, key) in query::index_keys(&commitment, hash)? {
+                batch.delete(column, key);
+            }
+            push_order_event(batch, kind, &commitment);
+        }
+        Ok(())
+    }
+
+    /// Remove order commitment and its index entries by the order hash
+    fn remove_order_commitment(&self, hash: &H256, kind: OrderEventKind) -> Result<H256, Box<Error>> {
+        let mut batch = Batch::default();
+        self.push_order_removal(&mut batch, hash, kind)?;
+
+        let result = match self.commit(batch) {
+            Ok(_) => Ok(*hash),
             Err(e) => {
                 println!("Error writing order commitments: {}", e);
                 Err(Box::new(e))
@@ -215,9 +591,146 @@ pub trait AppStorage: Storage {
         result
     }
 
+    /// Remove the order and keep the signed cancel as a tombstone.
+    /// The cancel has to be verified with `order::verify_cancel` beforehand
+    fn cancel_order_commitment(&self, cancel: &CancelOrder) -> Result<H256, Box<Error>> {
+        let order_commitment = cancel.order_commitment
+            .as_ref()
+            .ok_or_else(|| Error::InvalidOrder("cancelled order is missing".to_string()))?;
+        let hash = order::order_hash(order_commitment)?;
+
+        let mut batch = Batch::default();
+        self.push_order_removal(&mut batch, &hash, OrderEventKind::Cancelled)?;
+        batch.put(Column::Tombstones, hash.as_bytes(), encode(cancel));
+
+        let result = match self.commit(batch) {
+            Ok(_) => Ok(hash),
+            Err(e) => {
+                println!("Error writing order commitments: {}", e);
+                Err(Box::new(e))
+            }
+        };
+        result
+    }
+
+    /// Check if the order was cancelled
+    fn is_cancelled(&self, hash: &H256) -> Result<bool, Box<Error>> {
+        Ok(self.contains(Column::Tombstones, hash.as_bytes())?)
+    }
+
+    /// Get signed cancel of the order
+    fn tombstone(&self, hash: &H256) -> Result<Option<CancelOrder>, Box<Error>> {
+        Ok(self.get::<CancelOrder>(Column::Tombstones, hash.as_bytes())?)
+    }
+
+    /// Remove tombstones of orders that are expired at `now`,
+    /// since expired orders are rejected anyway
+    fn prune_tombstones(&self, now: u64) -> Result<usize, Box<Error>> {
+        let mut batch = Batch::default();
+
+        for (key, value) in self.iterator(Column::Tombstones, IteratorMode::Start) {
+            let cancel = CancelOrder::decode(&mut Cursor::new(value.as_ref())).unwrap();
+            let expired = cancel.order_commitment
+                .as_ref()
+                .map_or(true, |commitment| order::is_expired(commitment, now));
+            if expired {
+                batch.delete(Column::Tombstones, key);
+            }
+        }
+
+        let pruned = batch.ops.len();
+        self.commit(batch)?;
+        Ok(pruned)
+    }
+
+    /// Open orders a `Match` event of the Marketplace settled, for when the matched orders
+    /// can't be read from the transaction: sell orders of the seller and buy orders of the buyer for the NFT
+    fn matched_orders(
+        &self,
+        contract: Address,
+        nft_id: U256,
+        seller: Address,
+        buyer: Address,
+    ) -> Result<Vec<OrderCommitment>, Box<Error>> {
+        let mut matched = Vec::new();
+        for commitment in self.orders_for_token(contract, nft_id)?.order_commitments {
+            let signer = commitment.signer.parse::<Address>().unwrap_or_default();
+            let is_buy = commitment.order_type == order_commitment::OrderType::Buy as i32;
+            if (signer == seller && !is_buy) || (signer == buyer && is_buy) {
+                matched.push(commitment);
+            }
+        }
+        Ok(matched)
+    }
+
+    /// Remove orders settled by the Marketplace.
+    /// They're kept as unsigned tombstones, so a late gossip can't bring them back.
+    /// Returns hashes of the matched orders
+    fn remove_matched_orders(&self, order_commitments: &[OrderCommitment]) -> Result<Vec<H256>, Box<Error>> {
+        let mut matched = Vec::new();
+        let mut batch = Batch::default();
+
+        for commitment in order_commitments {
+            let hash = order::order_hash(commitment)?;
+            self.push_order_removal(&mut batch, &hash, OrderEventKind::Matched)?;
+            let tombstone = CancelOrder {
+                order_id: order::order_id(&hash),
+                signature: None,
+                order_commitment: Some(commitment.clone()),
+            };
+            batch.put(Column::Tombstones, hash.as_bytes(), encode(&tombstone));
+            matched.push(hash);
+        }
+
+        self.commit(batch)?;
+        Ok(matched)
+    }
+
+    /// Key of the latest order event
+    fn latest_order_event_key(&self) -> Option<Vec<u8>> {
+        self.iterator(Column::OrderEvents, IteratorMode::End)
+            .next()
+            .map(|(key, _)| key.to_vec())
+    }
+
+    /// Get up to `limit` order events that happened after the event with `cursor` key.
+    /// Empty cursor starts from the oldest event
+    fn order_events_after(&self, cursor: &[u8], limit: usize) -> Result<Vec<(Vec<u8>, OrderEvent)>, Box<Error>> {
+        let iter = self.iterator(Column::OrderEvents, IteratorMode::From(cursor, Direction::Forward));
+
+        let events = iter
+            .filter(|(key, _)| key.as_ref() != cursor)
+            .take(limit)
+            .map(|(key, value)| {
+                let event = OrderEvent::decode(&mut Cursor::new(value.as_ref())).unwrap();
+                (key.to_vec(), event)
+            })
+            .collect();
+
+        Ok(events)
+    }
+
+    /// Remove order events recorded before the unix timestamp.
+    /// Events are in the order of their sequence numbers, so timestamps don't decrease
+    fn prune_order_events(&self, before: u64) -> Result<usize, Box<Error>> {
+        let mut batch = Batch::default();
+
+        for (key, value) in self.iterator(Column::OrderEvents, IteratorMode::Start) {
+            let event = OrderEvent::decode(&mut Cursor::new(value.as_ref())).unwrap();
+            if event.timestamp >= before {
+                break;
+            }
+            batch.delete(Column::OrderEvents, key);
+        }
+
+        let pruned = batch.ops.len();
+        self.commit(batch)?;
+        Ok(pruned)
+    }
+
     /// Get current slot number
     fn slot_number(&self) -> u128 {
-        match self.read("slot_number") {
+        match self.read(Column::Slots, "slot_number") {
             Ok(Some(v)) => {
                 let mut bytes: [u8; 16] = Default::default();
                 bytes.copy_from_slice(&v);
@@ -226,7 +739,7 @@ pub trait AppStorage: Storage {
             },
             _ => {
                 let slot_number: u128 = 0;
-                self.write("slot_number", slot_number.to_be_bytes().as_slice())
+                self.write(Column::Slots, "slot_number", slot_number.to_be_bytes().as_slice())
                     .expect("Error writing slot number");
                 
                 slot_number
@@ -236,13 +749,13 @@ pub trait AppStorage: Storage {
 
     /// Set current slot number
     fn set_slot_number(&self, slot_number: u128) {
-        self.write("slot_number", slot_number.to_be_bytes().as_slice())
+        self.write(Column::Slots, "slot_number", slot_number.to_be_bytes().as_slice())
             .expect("Error writing slot number");
     }
 
     /// Get node's address
     fn address(&self) -> Address {
-        match self.read("address") {
+        match self.read(Column::Meta, "address") {
             Ok(Some(v)) => {
                 Address::from_slice(v.as_slice())
             },
@@ -253,10 +766,25 @@ pub trait AppStorage: Storage {
         }
     }
 
-    /// Get current main processor
+    /// Record the validator registered in the auction protocol
+    fn set_registered_validator(&self, address: &Address) -> Result<(), Error> {
+        self.write(Column::Validators, address.as_bytes(), b"")
+    }
+
+    /// Check if the address is registered as a validator in the auction protocol
+    fn is_registered_validator(&self, address: &Address) -> bool {
+        self.contains(Column::Validators, address.as_bytes()).unwrap_or(false)
+    }
+
+    /// Set current main processor
+    fn set_processor_address(&self, address: &Address) -> Result<(), Error> {
+        self.write(Column::Slots, "current_processor", address.as_bytes())
+    }
+
+    /// Get current main processor, the default one until the first slot winner is known
     fn current_processor(&self) -> Address {
-        match self.read("current_processor") {
-            Ok(Some(v)) => {
+        match self.read(Column::Slots, "current_processor") {
+            Ok(Some(v)) if v.len() == Address::len_bytes() => {
                 Address::from_slice(v.as_slice())
             },
             _ => {
@@ -265,4 +793,38 @@ pub trait AppStorage: Storage {
             }
         }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_subscriber_receives_the_event() {
+        let events = NetworkEvents::default();
+        let first = events.subscribe();
+        let second = events.subscribe();
+
+        let peer_id = PeerId::random();
+        events.publish(NetworkEvent::PeerDisconnected { peer_id });
+
+        for receiver in [first, second].iter() {
+            match receiver.try_recv() {
+                Ok(NetworkEvent::PeerDisconnected { peer_id: received }) => assert_eq!(received, peer_id),
+                other => panic!("unexpected event {:?}", other),
+            }
+        }
+    }
+
+    #[test]
+    fn closed_subscribers_are_removed() {
+        let events = NetworkEvents::default();
+        let open = events.subscribe();
+        drop(events.subscribe());
+
+        events.publish(NetworkEvent::PeerDisconnected { peer_id: PeerId::random() });
+
+        assert_eq!(events.subscribers.lock().unwrap().len(), 1);
+        assert!(open.try_recv().is_ok());
+    }
+}
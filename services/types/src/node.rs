@@ -1,5 +1,6 @@
 use ethers::prelude::LocalWallet;
-use libp2p::{PeerId};
+use libp2p::{Multiaddr, PeerId};
+use libp2p::multiaddr::Protocol;
 use libp2p::identity::Keypair;
 use crate::Address;
 
@@ -21,6 +22,16 @@ pub struct P2pNode {
     pub eth_remote_url: String,
     pub auction_address: Address,
     pub marketplace_address: Address,
+    /// Request open orders from peers on startup
+    pub sync: bool,
+    /// Multiaddrs of the nodes dialed on startup
+    pub bootnodes: Vec<Multiaddr>,
+    /// Multiaddrs the node listens on, any free tcp port if empty
+    pub listen_addresses: Vec<Multiaddr>,
+    /// Publicly reachable multiaddr of the node
+    pub external_address: Option<Multiaddr>,
+    /// Name and version of the node binary reported to peers
+    pub agent_version: String,
 }
 
 impl Default for P2pNode {
@@ -43,6 +54,11 @@ impl Default for P2pNode {
             eth_remote_url: "http://127.0.0.1:8545".to_string(),
             auction_address: Address::zero(),
             marketplace_address: Address::zero(),
+            sync: false,
+            bootnodes: vec![],
+            listen_addresses: vec![],
+            external_address: None,
+            agent_version: "nft-node".to_string(),
         }
     }
 }
@@ -59,6 +75,11 @@ impl P2pNode {
         eth_remote_url: String,
         auction_address: Address,
         marketplace_address: Address,
+        sync: bool,
+        bootnodes: Vec<Multiaddr>,
+        listen_addresses: Vec<Multiaddr>,
+        external_address: Option<Multiaddr>,
+        agent_version: String,
     ) -> Self {
         P2pNode {
             name,
@@ -71,6 +92,11 @@ impl P2pNode {
             eth_remote_url,
             auction_address,
             marketplace_address,
+            sync,
+            bootnodes,
+            listen_addresses,
+            external_address,
+            agent_version,
         }
     }
 
@@ -83,6 +109,21 @@ impl P2pNode {
         self.node_type == NodeType::FullNode
     }
 
+    /// URL registered in the auction protocol:
+    /// external multiaddr with the peer id, or the gRPC endpoint if it's not configured
+    pub fn node_url(&self, g_rpc_endpoint: &str) -> String {
+        match &self.external_address {
+            Some(address) => {
+                let mut address = address.clone();
+                if !address.iter().any(|protocol| matches!(protocol, Protocol::P2p(_))) {
+                    address.push(Protocol::P2p(self.peer_id.into()));
+                }
+                address.to_string()
+            },
+            None => g_rpc_endpoint.to_string(),
+        }
+    }
+
     pub fn get_wallet (&self) -> LocalWallet {
         self.wallet.clone()
     }
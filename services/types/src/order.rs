@@ -0,0 +1,209 @@
+use ethers::abi::{self, Token};
+use ethers::types::{H256, U256, RecoveryMessage, Signature as EthSignature};
+use ethers::utils::keccak256;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::{Address, CancelOrder, Error, OrderCommitment};
+use crate::node_rpc::Signature;
+
+/// Parse an address field of the order commitment
+fn parse_address(field: &str, value: &str) -> Result<Address, Error> {
+    value.parse::<Address>()
+        .map_err(|_| Error::InvalidOrder(format!("invalid {} address: {}", field, value)))
+}
+
+/// Parse unsigned integer field given as decimal or `0x` prefixed hex string.
+/// Values have to fit into `uint128` used by the Marketplace contract
+pub fn parse_uint128(field: &str, value: &str) -> Result<U256, Error> {
+    let value = value.trim();
+    let parsed = match value.strip_prefix("0x") {
+        Some(hex) if !hex.is_empty() => U256::from_str_radix(hex, 16).ok(),
+        Some(_) => None,
+        None if value.is_empty() || value.starts_with('-') || value.starts_with('+') => None,
+        None => U256::from_dec_str(value).ok(),
+    };
+
+    match parsed {
+        Some(number) if number <= U256::from(u128::MAX) => Ok(number),
+        Some(_) => Err(Error::InvalidOrder(format!("{} is out of range: {}", field, value))),
+        None => Err(Error::InvalidOrder(format!("invalid {}: {}", field, value))),
+    }
+}
+
+/// Price of the order in payment tokens
+pub fn price(order: &OrderCommitment) -> Result<U256, Error> {
+    parse_uint128("price", &order.price)
+}
+
+/// Token id of the NFT
+pub fn nft_id(order: &OrderCommitment) -> Result<U256, Error> {
+    parse_uint128("nft id", &order.nft_id)
+}
+
+/// Make sure all fields of the order commitment are well-formed
+pub fn validate_fields(order: &OrderCommitment) -> Result<(), Error> {
+    hash_for_signature(order).map(|_| ())
+}
+
+/// Hash order commitment the same way as `Marketplace.hashForSignature`,
+/// i.e keccak256 of `abi.encode` of all the order fields
+pub fn hash_for_signature(order: &OrderCommitment) -> Result<H256, Error> {
+    let tokens = vec![
+        Token::Address(parse_address("signer", &order.signer)?),
+        Token::Address(parse_address("taker", &order.taker)?),
+        Token::Address(parse_address("contract", &order.contract_address)?),
+        Token::Address(parse_address("token", &order.token_address)?),
+        Token::Uint(nft_id(order)?),
+        Token::Address(parse_address("gossiper", &order.gossiper)?),
+        Token::Uint(price(order)?),
+        Token::Uint(U256::from(order.order_type)),
+        Token::Uint(U256::from(order.expires_at)),
+        Token::Uint(U256::from(order.nonce)),
+        Token::Uint(U256::from(order.salt)),
+    ];
+
+    Ok(H256::from(keccak256(abi::encode(&tokens))))
+}
+
+/// Current unix timestamp in seconds
+pub fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+/// Check if the order is expired at the given unix timestamp.
+/// Orders with `expires_at` of 0 never expire
+pub fn is_expired(order: &OrderCommitment, now: u64) -> bool {
+    order.expires_at != 0 && order.expires_at < now
+}
+
+/// Canonical identity of the order commitment.
+/// Equals the key of the order in `Marketplace.cancelledOrMatched`
+pub fn order_hash(order: &OrderCommitment) -> Result<H256, Error> {
+    hash_for_signature(order)
+}
+
+/// Format order hash as the `order_id` of the commitment
+pub fn order_id(hash: &H256) -> String {
+    format!("{:?}", hash)
+}
+
+/// Parse `order_id` of the commitment back into the order hash
+pub fn parse_order_id(order_id: &str) -> Result<H256, Error> {
+    order_id.parse::<H256>()
+        .map_err(|_| Error::InvalidOrder(format!("invalid order id: {}", order_id)))
+}
+
+/// Convert protobuf signature into ethers signature
+fn to_eth_signature(signature: &Signature) -> Result<EthSignature, Error> {
+    if signature.r.len() != 32 || signature.s.len() != 32 {
+        return Err(Error::InvalidSignature("r and s must be 32 bytes long".to_string()));
+    }
+    if signature.v.is_empty() || signature.v.len() > 8 {
+        return Err(Error::InvalidSignature("invalid recovery id".to_string()));
+    }
+
+    let v = signature.v.iter().fold(0u64, |acc, byte| (acc << 8) | *byte as u64);
+
+    Ok(EthSignature {
+        r: U256::from_big_endian(&signature.r),
+        s: U256::from_big_endian(&signature.s),
+        v,
+    })
+}
+
+/// Recover the address that signed the order commitment
+pub fn recover_signer(order: &OrderCommitment) -> Result<Address, Error> {
+    let signature = order.signature
+        .as_ref()
+        .ok_or_else(|| Error::InvalidSignature("order is not signed".to_string()))?;
+
+    let hash = hash_for_signature(order)?;
+
+    to_eth_signature(signature)?
+        .recover(RecoveryMessage::Hash(hash))
+        .map_err(|e| Error::InvalidSignature(e.to_string()))
+}
+
+/// Make sure the order commitment is signed by its `signer`
+pub fn verify_signature(order: &OrderCommitment) -> Result<(), Error> {
+    let signer = parse_address("signer", &order.signer)?;
+    let recovered = recover_signer(order)?;
+
+    if recovered != signer {
+        return Err(Error::InvalidSignature(
+            format!("order signed by {:?}, expected {:?}", recovered, signer)
+        ));
+    }
+
+    Ok(())
+}
+
+/// Hash signed by the order signer to cancel the order
+pub fn cancel_hash(order_hash: &H256) -> H256 {
+    let type_hash = keccak256("CancelOrder(bytes32 orderHash)");
+    let tokens = vec![
+        Token::FixedBytes(type_hash.to_vec()),
+        Token::FixedBytes(order_hash.as_bytes().to_vec()),
+    ];
+    H256::from(keccak256(abi::encode(&tokens)))
+}
+
+/// Make sure the cancel request is signed by the signer of the attached order.
+/// Returns hash of the cancelled order
+pub fn verify_cancel(cancel: &CancelOrder) -> Result<H256, Error> {
+    let order = cancel.order_commitment
+        .as_ref()
+        .ok_or_else(|| Error::InvalidOrder("cancelled order is missing".to_string()))?;
+    let hash = order_hash(order)?;
+
+    if !cancel.order_id.is_empty() && parse_order_id(&cancel.order_id)? != hash {
+        return Err(Error::InvalidOrder(format!("order id doesn't match the order: {}", cancel.order_id)));
+    }
+
+    let signature = cancel.signature
+        .as_ref()
+        .ok_or_else(|| Error::InvalidSignature("cancel is not signed".to_string()))?;
+    let signer = parse_address("signer", &order.signer)?;
+    let recovered = to_eth_signature(signature)?
+        .recover(RecoveryMessage::Hash(cancel_hash(&hash)))
+        .map_err(|e| Error::InvalidSignature(e.to_string()))?;
+
+    if recovered != signer {
+        return Err(Error::InvalidSignature(
+            format!("cancel signed by {:?}, expected {:?}", recovered, signer)
+        ));
+    }
+
+    Ok(hash)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hash_matches_marketplace_hash_for_signature() {
+        let order = OrderCommitment {
+            signer: "0xa9D93b8154A38e35Bd003294393cC29398825996".to_string(),
+            taker: "0x0000000000000000000000000000000000000001".to_string(),
+            contract_address: "0x3B92d83A02465F52F80d1265aBaDeF29056dcB48".to_string(),
+            token_address: "0x2B31AF0a19c5a01a0ca5A300b85977aFE8bf4acA".to_string(),
+            nft_id: "1".to_string(),
+            gossiper: "0x9fEf2AA08cde1EB360c35600E14C453BdF8DCf7B".to_string(),
+            price: "12313".to_string(),
+            order_type: 1,
+            expires_at: 1700000000,
+            nonce: 7,
+            salt: 42,
+            ..Default::default()
+        };
+
+        // Marketplace.hashForSignature of the same order
+        let expected: H256 = "0x30117898b92e8a42b627d08bbdd94ad0260671f5b0a956f615749453b404c4a6".parse().unwrap();
+        assert_eq!(hash_for_signature(&order).unwrap(), expected);
+        assert_eq!(order_hash(&order).unwrap(), expected);
+    }
+}
@@ -0,0 +1,245 @@
+use crate::{Address, Column, Error, H256, U256, OrderCommitment, hex, order};
+use crate::node_rpc::OrderFilter;
+use std::convert::TryInto;
+
+/// Page size used when the request doesn't specify one
+pub const DEFAULT_PAGE_SIZE: usize = 100;
+/// Upper bound for the page size of a single request
+pub const MAX_PAGE_SIZE: usize = 1000;
+
+/// Every index key ends with the order hash
+const HASH_LEN: usize = 32;
+
+/// Big endian encoding of the number, so lexicographic order of keys
+/// is the same as the numeric order
+fn encode_uint(value: &U256) -> [u8; 32] {
+    let mut bytes = [0u8; 32];
+    value.to_big_endian(&mut bytes);
+    bytes
+}
+
+fn concat(parts: &[&[u8]]) -> Vec<u8> {
+    parts.concat()
+}
+
+fn parse_address(field: &str, value: &str) -> Result<Option<Address>, Error> {
+    if value.is_empty() {
+        return Ok(None);
+    }
+    value.parse::<Address>()
+        .map(Some)
+        .map_err(|_| Error::InvalidQuery(format!("invalid {} address: {}", field, value)))
+}
+
+/// Parse optional unsigned integer filter, empty value means no filter
+fn parse_uint(field: &str, value: &str) -> Result<Option<U256>, Error> {
+    if value.is_empty() {
+        return Ok(None);
+    }
+    order::parse_uint128(field, value).map(Some)
+}
+
+/// Secondary index entries of the order commitment with the given hash.
+///  - `NftIndex`: contract ++ nft id ++ hash
+///  - `SignerIndex`: signer ++ hash
+///  - `PriceIndex`: payment token ++ price ++ hash
+///  - `ExpiryIndex`: expires at ++ hash, only for orders that expire
+pub fn index_keys(order_commitment: &OrderCommitment, hash: &H256) -> Result<Vec<(Column, Vec<u8>)>, Error> {
+    let contract = order_commitment.contract_address.parse::<Address>()
+        .map_err(|_| Error::InvalidOrder(format!("invalid contract address: {}", order_commitment.contract_address)))?;
+    let signer = order_commitment.signer.parse::<Address>()
+        .map_err(|_| Error::InvalidOrder(format!("invalid signer address: {}", order_commitment.signer)))?;
+    let token = order_commitment.token_address.parse::<Address>()
+        .map_err(|_| Error::InvalidOrder(format!("invalid token address: {}", order_commitment.token_address)))?;
+    let nft_id = encode_uint(&order::nft_id(order_commitment)?);
+    let price = encode_uint(&order::price(order_commitment)?);
+
+    let mut keys = vec![
+        (Column::NftIndex, concat(&[contract.as_bytes(), &nft_id, hash.as_bytes()])),
+        (Column::SignerIndex, concat(&[signer.as_bytes(), hash.as_bytes()])),
+        (Column::PriceIndex, concat(&[token.as_bytes(), &price, hash.as_bytes()])),
+    ];
+    if order_commitment.expires_at != 0 {
+        keys.push((Column::ExpiryIndex, concat(&[&order_commitment.expires_at.to_be_bytes(), hash.as_bytes()])));
+    }
+    Ok(keys)
+}
+
+/// Range of the expiry index with the orders expired at `now`
+pub fn expired_range(now: u64) -> KeyRange {
+    KeyRange {
+        column: Column::ExpiryIndex,
+        prefix: Vec::new(),
+        start: Vec::new(),
+        end: Some(now.to_be_bytes().to_vec()),
+    }
+}
+
+/// Key of the order event: big endian sequence number assigned by the storage on commit,
+/// so the feed is in commit order whichever writer records the event
+pub fn event_key(sequence: u64) -> Vec<u8> {
+    sequence.to_be_bytes().to_vec()
+}
+
+/// Sequence number of the order event key, `None` if it's malformed
+pub fn event_sequence(key: &[u8]) -> Option<u64> {
+    let bytes: [u8; 8] = key.try_into().ok()?;
+    Some(u64::from_be_bytes(bytes))
+}
+
+/// Extract order hash from the primary or index key
+pub fn hash_from_key(key: &[u8]) -> Result<H256, Error> {
+    if key.len() < HASH_LEN {
+        return Err(Error::Other(format!("malformed order key: {:?}", key)));
+    }
+    Ok(H256::from_slice(&key[key.len() - HASH_LEN..]))
+}
+
+/// Clamp requested page size
+pub fn page_size(requested: u32) -> usize {
+    match requested as usize {
+        0 => DEFAULT_PAGE_SIZE,
+        size => size.min(MAX_PAGE_SIZE),
+    }
+}
+
+/// Range of keys to walk in order to serve a query
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeyRange {
+    /// Column family holding the keys
+    pub column: Column,
+    /// All keys of the range share this prefix
+    pub prefix: Vec<u8>,
+    /// First key of the range
+    pub start: Vec<u8>,
+    /// Exclusive upper bound of the range
+    pub end: Option<Vec<u8>>,
+}
+
+impl KeyRange {
+    fn prefixed(column: Column, prefix: Vec<u8>) -> Self {
+        KeyRange { column, start: prefix.clone(), prefix, end: None }
+    }
+
+    /// Check if the key belongs to the range
+    pub fn contains(&self, key: &[u8]) -> bool {
+        key.starts_with(&self.prefix)
+            && self.end.as_ref().map_or(true, |end| key < end.as_slice())
+    }
+
+    /// Key to start iterating from, given the hex cursor of the previous page
+    pub fn start_from(&self, cursor: &str) -> Result<Vec<u8>, Error> {
+        if cursor.is_empty() {
+            return Ok(self.start.clone());
+        }
+        match hex::decode(cursor).ok() {
+            Some(key) if self.contains(&key) => Ok(key),
+            _ => Err(Error::InvalidQuery(format!("cursor doesn't match the filter: {}", cursor))),
+        }
+    }
+}
+
+/// Parsed and validated `OrderFilter`
+#[derive(Debug, Clone, Default)]
+pub struct OrderSelector {
+    pub contract: Option<Address>,
+    pub nft_id: Option<U256>,
+    pub token: Option<Address>,
+    pub signer: Option<Address>,
+    pub order_types: Vec<i32>,
+    pub min_price: Option<U256>,
+    pub max_price: Option<U256>,
+}
+
+impl OrderSelector {
+    /// Parse the filter, missing filter matches all orders
+    pub fn parse(filter: Option<&OrderFilter>) -> Result<Self, Error> {
+        let filter = match filter {
+            Some(filter) => filter,
+            None => return Ok(OrderSelector::default()),
+        };
+
+        let selector = OrderSelector {
+            contract: parse_address("contract", &filter.contract_address)?,
+            nft_id: parse_uint("nft id", &filter.nft_id)?,
+            token: parse_address("token", &filter.token_address)?,
+            signer: parse_address("signer", &filter.signer)?,
+            order_types: filter.order_types.clone(),
+            min_price: parse_uint("min price", &filter.min_price)?,
+            max_price: parse_uint("max price", &filter.max_price)?,
+        };
+
+        if selector.nft_id.is_some() && selector.contract.is_none() {
+            return Err(Error::InvalidQuery("nft id filter requires contract address".to_string()));
+        }
+
+        Ok(selector)
+    }
+
+    /// Pick the most selective index for the filter
+    pub fn key_range(&self) -> KeyRange {
+        if let Some(contract) = &self.contract {
+            let prefix = match &self.nft_id {
+                Some(nft_id) => concat(&[contract.as_bytes(), &encode_uint(nft_id)]),
+                None => contract.as_bytes().to_vec(),
+            };
+            return KeyRange::prefixed(Column::NftIndex, prefix);
+        }
+
+        if let Some(signer) = &self.signer {
+            return KeyRange::prefixed(Column::SignerIndex, signer.as_bytes().to_vec());
+        }
+
+        if let Some(token) = &self.token {
+            let prefix = token.as_bytes().to_vec();
+            let start = match &self.min_price {
+                Some(min_price) => concat(&[&prefix, &encode_uint(min_price)]),
+                None => prefix.clone(),
+            };
+            let end = self.max_price
+                .and_then(|max_price| max_price.checked_add(U256::one()))
+                .map(|bound| concat(&[&prefix, &encode_uint(&bound)]));
+            return KeyRange { column: Column::PriceIndex, prefix, start, end };
+        }
+
+        KeyRange::prefixed(Column::Orders, Vec::new())
+    }
+
+    /// Check if the order satisfies every condition of the filter
+    pub fn matches(&self, order_commitment: &OrderCommitment) -> bool {
+        let address_matches = |expected: &Option<Address>, value: &str| {
+            expected.map_or(true, |expected| value.parse::<Address>().map_or(false, |a| a == expected))
+        };
+
+        if !address_matches(&self.contract, &order_commitment.contract_address)
+            || !address_matches(&self.token, &order_commitment.token_address)
+            || !address_matches(&self.signer, &order_commitment.signer)
+        {
+            return false;
+        }
+
+        if !self.order_types.is_empty() && !self.order_types.contains(&order_commitment.order_type) {
+            return false;
+        }
+
+        if let Some(nft_id) = &self.nft_id {
+            if order::nft_id(order_commitment).map_or(true, |id| &id != nft_id) {
+                return false;
+            }
+        }
+
+        if self.min_price.is_some() || self.max_price.is_some() {
+            let price = match order::price(order_commitment) {
+                Ok(price) => price,
+                Err(_) => return false,
+            };
+            if self.min_price.map_or(false, |min| price < min)
+                || self.max_price.map_or(false, |max| price > max)
+            {
+                return false;
+            }
+        }
+
+        true
+    }
+}
@@ -2,6 +2,9 @@ use async_std::channel::{Sender};
 use ethers::core::rand::Rng;
 use futures::{FutureExt, select, TryFutureExt};
 use ethers::{prelude::*};
+use ethers::abi::RawLog;
+use ethers::abi::AbiDecode;
+use ethers::contract::EthLogDecode;
 use common_types::{Error, NetworkMessage, Storage, AppStorage, OrderCommitment};
 use std::convert::TryFrom;
 use std::time::Duration;
@@ -37,8 +40,8 @@ pub struct Web3<DB: Storage> {
     pub topic: Option<ethers::types::H256>,
     // database
     pub db: Arc<DB>,
-    // grpc address
-    pub grpc_addr: String,
+    // node url registered in the auction protocol
+    pub node_url: String,
 }
 
 impl<DB> Web3<DB>
@@ -52,7 +55,7 @@ impl<DB> Web3<DB>
         marketplace: ethers::types::Address,
         topic: Option<ethers::types::H256>,
         db: Arc<DB>,
-        grpc_addr: String,
+        node_url: String,
     ) -> Self {
         // intstantiate web3 provider
         let provider = Provider::<Http>::try_from(remote_url.to_owned())
@@ -70,7 +73,7 @@ impl<DB> Web3<DB>
             marketplace,
             topic,
             db,
-            grpc_addr
+            node_url
         }
     }
 
@@ -94,6 +97,55 @@ impl<DB> Web3<DB>
         marketplace
     }
 
+    /// Buy and sell orders of the `matchOrder` transaction,
+    /// `None` if the transaction isn't a direct call of the marketplace
+    async fn orders_of_transaction(&self, tx_hash: Option<H256>) -> Option<Vec<OrderCommitment>> {
+        let tx = match self.provider.get_transaction(tx_hash?).await {
+            Ok(tx) => tx?,
+            Err(e) => {
+                println!("[WEB3] Error fetching match transaction: {:?}", e);
+                return None;
+            }
+        };
+        let call = MatchOrderCall::decode(&tx.input).ok()?;
+        Some(vec![
+            to_order_commitment(call.buy, call.buy_signature),
+            to_order_commitment(call.sell, call.sell_signature),
+        ])
+    }
+
+    /// Record the validator registered in the auction protocol
+    fn register_validator(&self, node: Address) {
+        match self.db.set_registered_validator(&node) {
+            Ok(_) => println!("[WEB3] Registered validator {:?}", node),
+            Err(e) => println!("[WEB3] Error storing validator {:?}: {}", node, e),
+        }
+    }
+
+    /// Store validators from all `NewNode` events of the auction protocol
+    pub async fn load_registered_validators(&self) {
+        let filter = Filter::default()
+            .address(ValueOrArray::Value(self.auction))
+            .from_block(0);
+
+        let logs = match self.provider.get_logs(&filter).await {
+            Ok(logs) => logs,
+            Err(e) => {
+                println!("[WEB3] Error loading registered validators: {:?}", e);
+                return;
+            }
+        };
+        for log in logs {
+            let raw_log = RawLog {
+                topics: log.topics.clone(),
+                data: log.data.to_vec(),
+            };
+            if let Ok(new_node) = <NewNodeFilter as EthLogDecode>::decode_log(&raw_log) {
+                self.register_validator(new_node.node);
+            }
+        }
+    }
+
     /// Launches the subscription service.
     /// Tracks new blocks and new transactions.
     /// For each block, checks the current winner of the slot
@@ -118,7 +170,7 @@ impl<DB> Web3<DB>
         let register_tx = auction
             .method::<_, ()>(
                 "registerValidator",
-                (self.wallet.address().to_owned(), self.grpc_addr.clone()),
+                (self.wallet.address().to_owned(), self.node_url.clone()),
             )
             .unwrap()
             .legacy()
@@ -151,6 +203,9 @@ impl<DB> Web3<DB>
             }
         }
 
+        // validators registered before the node started
+        self.load_registered_validators().await;
+
         // Watch new blocks
         let mut block_stream = provider
             .watch_blocks()
@@ -208,7 +263,10 @@ impl<DB> Web3<DB>
                             .unwrap();
 
                         // send new message about current processor
-                        if self.sender.send(NetworkMessage::CurrentProcessor{address}).await.is_err() {
+                        if self.sender.send(NetworkMessage::CurrentProcessor{
+                            address,
+                            node_url,
+                        }).await.is_err() {
                             println!("[WEB3] Error sending message to network");
                         };
 
@@ -292,6 +350,30 @@ impl<DB> Web3<DB>
                     // if it is, send the message to the network
                     Some(raw_event) => {
                         println!("[WEB3] New event received: {:?}", raw_event.data);
+                        let raw_log = RawLog {
+                            topics: raw_event.topics.clone(),
+                            data: raw_event.data.to_vec(),
+                        };
+                        // settled orders are removed from the order book
+                        if let Ok(matched) = <MatchFilter as EthLogDecode>::decode_log(&raw_log) {
+                            let order_commitments = match self.orders_of_transaction(raw_event.transaction_hash).await {
+                                Some(order_commitments) => order_commitments,
+                                None => self.db.matched_orders(
+                                    matched.contract_address,
+                                    U256::from(matched.nft_id),
+                                    matched.signer,
+                                    matched.taker,
+                                ).unwrap_or_else(|e| {
+                                    println!("[WEB3] Error looking up matched orders: {}", e);
+                                    Vec::new()
+                                }),
+                            };
+                            if self.sender.send(NetworkMessage::OrdersMatched {
+                                order_commitments
+                            }).await.is_err() {
+                                println!("[WEB3] Error sending matched orders");
+                            }
+                        }
                     },
                     None => {},
                 },
@@ -300,6 +382,14 @@ impl<DB> Web3<DB>
                     // check if it is an NewBid event
                     Some(raw_event) => {
                         println!("[WEB3] New auction event received: {:?}", raw_event.topics);
+                        let raw_log = RawLog {
+                            topics: raw_event.topics.clone(),
+                            data: raw_event.data.to_vec(),
+                        };
+                        // only registered validators may relay orders
+                        if let Ok(new_node) = <NewNodeFilter as EthLogDecode>::decode_log(&raw_log) {
+                            self.register_validator(new_node.node);
+                        }
                     },
                     None => {},
                 }
@@ -307,3 +397,26 @@ impl<DB> Web3<DB>
         }
     }
 }
+
+/// Order commitment of the order passed to the marketplace
+fn to_order_commitment(order: Order, signature: MarketplaceSignature) -> OrderCommitment {
+    OrderCommitment {
+        signer: format!("{:?}", order.signer),
+        taker: format!("{:?}", order.taker),
+        contract_address: format!("{:?}", order.contract_address),
+        token_address: format!("{:?}", order.token_address),
+        nft_id: order.nft_id.to_string(),
+        gossiper: format!("{:?}", order.gossiper),
+        price: order.price.to_string(),
+        order_type: order.order_type as i32,
+        expires_at: order.expires_at,
+        nonce: order.nonce,
+        salt: order.salt,
+        signature: Some(common_types::Signature {
+            v: vec![signature.v],
+            r: signature.r.to_vec(),
+            s: signature.s.to_vec(),
+        }),
+        ..Default::default()
+    }
+}